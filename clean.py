@@ -1,16 +1,62 @@
 #! /usr/bin/env python3
 
+# Drops history entries for files that don't exist anymore. This finds the
+# history the same way founder does, and with --profile NAME it cleans that
+# profile's file_history.NAME instead.
+
+import argparse
+import os
+import sys
 from os import path
 
-history_path = path.expanduser("~/.local/share/founder/file_history")
+
+# The same order as default_history_dir in src/history.rs. Empty variables are
+# skipped, and so is a relative $XDG_DATA_HOME.
+def history_dir():
+    if os.environ.get("FOUNDER_HISTORY_DIR"):
+        return os.environ["FOUNDER_HISTORY_DIR"]
+    xdg_data_home = os.environ.get("XDG_DATA_HOME", "")
+    if path.isabs(xdg_data_home):
+        return path.join(xdg_data_home, "founder")
+    if sys.platform == "darwin":
+        return path.expanduser("~/Library/Application Support/founder")
+    return path.expanduser("~/.local/share/founder")
+
+
+parser = argparse.ArgumentParser()
+parser.add_argument("--profile")
+args = parser.parse_args()
+file_name = "file_history"
+if args.profile:
+    file_name += "." + args.profile
+history_path = path.join(history_dir(), file_name)
 output = []
 
-with open(history_path) as f:
-    for line in f:
-        filepath = line.strip()
-        if path.exists(filepath):
-            output.append(filepath)
+with open(history_path, "rb") as f:
+    contents = f.read()
+
+# A history written with --history-null starts with a NUL byte, and then each
+# line ends with a NUL instead of a newline.
+if contents.startswith(b"\0"):
+    header, separator = b"\0", b"\0"
+    contents = contents[1:]
+else:
+    header, separator = b"", b"\n"
+
+for line in contents.split(separator):
+    if not line:
+        continue
+    # Newer lines are a timestamp, a tab, and then the path, possibly with
+    # more fields before the path, like a selection count or an @branch.
+    # Paths are absolute, so the path is the first field that starts with a /,
+    # the same as in parse_history_line.
+    filepath = line
+    while not filepath.startswith(b"/") and b"\t" in filepath:
+        filepath = filepath.split(b"\t", 1)[1]
+    if path.exists(filepath):
+        output.append(line)
 
-with open(history_path, "w") as f:
-    for filepath in output:
-        f.write(filepath + "\n")
+with open(history_path, "wb") as f:
+    f.write(header)
+    for line in output:
+        f.write(line + separator)
@@ -0,0 +1,375 @@
+// `founder bench` is a hidden subcommand for measuring the finder pipeline end
+// to end, with real fd and a scripted stand-in for fzf. It builds a synthetic
+// tree and history in a temp dir, so that numbers from different machines are
+// comparable, and then it runs the starting finder mode several times and
+// reports three times for each run, all measured from just before fd starts:
+//
+//   fd spawn: start_fd() returning, with fd running
+//   first paint: the stub receiving its first candidate line
+//   total: the stub exiting, after it has read every candidate
+//
+// The stub signals first paint by writing a byte to a FIFO that a thread here
+// is waiting on. The usual flags, given before the subcommand like
+// `founder --order interleaved bench`, apply to every run, so the same tree
+// can be compared with and without them.
+//
+// `founder bench --compaction` times the compactor instead, on a synthetic
+// history of --lines lines, most of them duplicates. It reports the dedup by
+// itself, with ahash and with the standard library's SipHash, and then all of
+// compact_history_file(), which also writes the compacted file.
+//
+// `founder bench --display-paths` times display_path() on --lines synthetic
+// paths, a third each under the cwd, elsewhere under home, and outside of
+// home. For comparison it also times the same calls with a starts_with()
+// check in front of each prefix, which is how display_path() used to find
+// them before it did a single strip_prefix() per prefix.
+
+use crate::{
+    display_path, finder_mode, history, run_finder_once, set_file_history_path_override, start_fd,
+    Config, Finder,
+};
+use ahash::AHashSet;
+use anyhow::{bail, Context, Result};
+use std::collections::HashSet;
+use std::ffi::CString;
+use std::fs;
+use std::io::prelude::*;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+const DEFAULT_FILES: usize = 20_000;
+const DEFAULT_RUNS: usize = 5;
+const DEFAULT_HISTORY_LINES: usize = 100_000;
+// The compaction benchmark's history has this many distinct paths.
+const DISTINCT_HISTORY_PATHS: usize = 20_000;
+// One history entry for every this many files, up to the usual history cap.
+const FILES_PER_HISTORY_ENTRY: usize = 20;
+
+struct Timings {
+    fd_spawn: Duration,
+    first_paint: Duration,
+    total: Duration,
+}
+
+// Deletes the temp dir, even if a run fails partway through.
+struct TempDir(PathBuf);
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+pub fn bench(mut config: Config, matches: &clap::ArgMatches) -> Result<()> {
+    if matches.is_present("history-file") {
+        bail!("bench makes its own history, so it doesn't take --history-file");
+    }
+    let files = matches
+        .value_of("files")
+        .map_or(DEFAULT_FILES, |n| n.parse().unwrap()); // already validated
+    let runs = matches
+        .value_of("runs")
+        .map_or(DEFAULT_RUNS, |n| n.parse().unwrap()); // already validated
+
+    let temp_dir =
+        TempDir(std::env::temp_dir().join(format!("founder-bench.{}", std::process::id())));
+    let _ = fs::remove_dir_all(&temp_dir.0);
+    if matches.is_present("compaction") {
+        let lines = matches
+            .value_of("lines")
+            .map_or(DEFAULT_HISTORY_LINES, |n| n.parse().unwrap()); // already validated
+        return bench_compaction(&config, &temp_dir.0, lines, runs);
+    }
+    if matches.is_present("display-paths") {
+        let lines = matches
+            .value_of("lines")
+            .map_or(DEFAULT_HISTORY_LINES, |n| n.parse().unwrap()); // already validated
+        return bench_display_paths(&config, lines, runs);
+    }
+    let tree = temp_dir.0.join("tree");
+    let history_entries = build_tree(&tree, files)?;
+    let history_path = temp_dir.0.join("file_history");
+    write_history(&history_path, &tree, files, history_entries)?;
+    set_file_history_path_override(history_path.as_os_str())?;
+    let fifo = temp_dir.0.join("painted");
+    make_fifo(&fifo)?;
+    let stub = temp_dir.0.join("fzf-stub");
+    write_stub(&stub, &fifo)?;
+
+    // The stub stands in for fzf, and it reads lines, so it needs newlines
+    // rather than --null. Nothing gets recorded, since the history is ours.
+    config.builtin = false;
+    config.finder = Finder::Fzf;
+    config.fzf_bin = Some(stub.into_os_string());
+    config.null = false;
+    config.tmux = false;
+    std::env::set_current_dir(&tree)?;
+
+    eprintln!(
+        "founder bench: {} files, {} history entries, {} runs",
+        files, history_entries, runs
+    );
+    eprintln!("run  fd spawn  first paint    total");
+    let mut all_timings = Vec::new();
+    for run in 1..=runs {
+        let timings = bench_once(&config, &fifo)?;
+        eprintln!(
+            "{:>3}  {:>8}  {:>11}  {:>7}",
+            run,
+            format_ms(timings.fd_spawn),
+            format_ms(timings.first_paint),
+            format_ms(timings.total)
+        );
+        all_timings.push(timings);
+    }
+    // The first run might be the only one with a cold page cache, so the
+    // median is the number to compare.
+    eprintln!(
+        "med  {:>8}  {:>11}  {:>7}",
+        format_ms(median(all_timings.iter().map(|t| t.fd_spawn))),
+        format_ms(median(all_timings.iter().map(|t| t.first_paint))),
+        format_ms(median(all_timings.iter().map(|t| t.total)))
+    );
+    Ok(())
+}
+
+fn bench_compaction(config: &Config, temp_dir: &Path, lines: usize, runs: usize) -> Result<()> {
+    fs::create_dir_all(temp_dir)?;
+    let history_path = temp_dir.join("file_history");
+    // Paths come back around in a scrambled order, so the most recent
+    // occurrence of each one is somewhere in the middle.
+    let mut history = Vec::new();
+    for i in 0..lines {
+        let n = i.wrapping_mul(7919) % DISTINCT_HISTORY_PATHS;
+        let line = format!(
+            "{}	/home/me/src/project{:02}/src/module{:03}/file{:06}.rs\n",
+            i + 1,
+            n % 100,
+            n % 1000,
+            n
+        );
+        history.extend_from_slice(line.as_bytes());
+    }
+    eprintln!(
+        "founder bench: {} history lines, {} distinct paths, {} runs",
+        lines,
+        DISTINCT_HISTORY_PATHS.min(lines),
+        runs
+    );
+    eprintln!("run  dedup (ahash)  dedup (siphash)  compact_history_file");
+    let mut all_timings = Vec::new();
+    for run in 1..=runs {
+        let start = Instant::now();
+        let mut ahash_set = AHashSet::new();
+        for entry in history::history_entries_from_most_recent(&history) {
+            ahash_set.insert(history::dedup_key(config.ignore_case_paths, entry.path));
+        }
+        let ahash_time = start.elapsed();
+
+        let start = Instant::now();
+        let mut siphash_set = HashSet::new();
+        for entry in history::history_entries_from_most_recent(&history) {
+            siphash_set.insert(history::dedup_key(config.ignore_case_paths, entry.path));
+        }
+        let siphash_time = start.elapsed();
+        assert_eq!(ahash_set.len(), siphash_set.len());
+
+        // Compaction rewrites the file, so every run starts from a fresh copy.
+        fs::write(&history_path, &history)?;
+        let start = Instant::now();
+        history::compact_history_file(
+            &history_path,
+            &history,
+            config.compact_retain_fraction,
+            config.ignore_case_paths,
+            config.max_history_bytes,
+            config.keep_duplicates,
+            config.history_null,
+        )?;
+        let compact_time = start.elapsed();
+
+        eprintln!(
+            "{:>3}  {:>13}  {:>15}  {:>20}",
+            run,
+            format_ms(ahash_time),
+            format_ms(siphash_time),
+            format_ms(compact_time)
+        );
+        all_timings.push((ahash_time, siphash_time, compact_time));
+    }
+    eprintln!(
+        "med  {:>13}  {:>15}  {:>20}",
+        format_ms(median(all_timings.iter().map(|t| t.0))),
+        format_ms(median(all_timings.iter().map(|t| t.1))),
+        format_ms(median(all_timings.iter().map(|t| t.2)))
+    );
+    Ok(())
+}
+
+fn bench_display_paths(config: &Config, lines: usize, runs: usize) -> Result<()> {
+    let cwd = Path::new("/home/me/src/project");
+    let home = Path::new("/home/me");
+    let paths: Vec<PathBuf> = (0..lines)
+        .map(|i| match i % 3 {
+            0 => cwd.join(format!("src/module{:03}/file{:06}.rs", i % 1000, i)),
+            1 => home.join(format!("notes/topic{:03}/note{:06}.md", i % 1000, i)),
+            _ => PathBuf::from(format!("/usr/share/doc/pkg{:03}/file{:06}", i % 1000, i)),
+        })
+        .collect();
+    eprintln!("founder bench: {} paths, {} runs", lines, runs);
+    eprintln!("run  display_path  with starts_with");
+    let mut all_timings = Vec::new();
+    for run in 1..=runs {
+        let start = Instant::now();
+        let mut total_len = 0;
+        for path in &paths {
+            total_len += display_path(path, cwd, Some(home), config.relative)?
+                .as_os_str()
+                .len();
+        }
+        let display_time = start.elapsed();
+
+        let start = Instant::now();
+        let mut checked_total_len = 0;
+        for path in &paths {
+            // Like before, home only gets checked for paths outside the cwd.
+            // black_box keeps the unused checks from being optimized out.
+            if !std::hint::black_box(path.starts_with(cwd)) {
+                std::hint::black_box(path.starts_with(home));
+            }
+            checked_total_len += display_path(path, cwd, Some(home), config.relative)?
+                .as_os_str()
+                .len();
+        }
+        let checked_time = start.elapsed();
+        assert_eq!(total_len, checked_total_len);
+
+        eprintln!(
+            "{:>3}  {:>12}  {:>16}",
+            run,
+            format_ms(display_time),
+            format_ms(checked_time)
+        );
+        all_timings.push((display_time, checked_time));
+    }
+    eprintln!(
+        "med  {:>12}  {:>16}",
+        format_ms(median(all_timings.iter().map(|t| t.0))),
+        format_ms(median(all_timings.iter().map(|t| t.1)))
+    );
+    Ok(())
+}
+
+fn bench_once(config: &Config, fifo: &Path) -> Result<Timings> {
+    // Opening the FIFO read-write doesn't wait for a writer, so this can't
+    // block, and we can write to it ourselves below if the stub never does.
+    // Whatever's left in it is discarded when the last copy is closed, so each
+    // run starts empty.
+    let fifo_file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(fifo)
+        .with_context(|| format!("failed to open {}", fifo.display()))?;
+    let mode = finder_mode(config, config.no_cwd_filter, false);
+    let start = Instant::now();
+    crossbeam_utils::thread::scope(|scope| {
+        let paint_thread = scope.spawn(|_| {
+            let mut byte = [0];
+            (&fifo_file).read_exact(&mut byte)?;
+            Ok::<Duration, std::io::Error>(start.elapsed())
+        });
+        let result = (|| {
+            let fd_reader = start_fd(config, &mode)?;
+            let fd_spawn = start.elapsed();
+            run_finder_once(config, &mode, fd_reader, None, None, "".as_ref())?;
+            Ok::<(Duration, Duration), anyhow::Error>((fd_spawn, start.elapsed()))
+        })();
+        // If there were no candidates, or something failed, the stub never
+        // wrote. Unblock the thread either way, and then first paint comes out
+        // the same as total.
+        (&fifo_file).write_all(b"x")?;
+        let first_paint = paint_thread.join().unwrap()?;
+        let (fd_spawn, total) = result?;
+        Ok::<Timings, anyhow::Error>(Timings {
+            fd_spawn,
+            first_paint: first_paint.min(total),
+            total,
+        })
+    })
+    .expect("panic in threading scope")
+}
+
+// 100 top-level directories with 10 subdirectories each, and the files spread
+// evenly over those. Returns how many history entries to make.
+fn build_tree(tree: &Path, files: usize) -> Result<usize> {
+    for i in 0..files {
+        let dir = tree.join(format!("d{:03}/s{:02}", i % 100, (i / 100) % 10));
+        if i < 1000 {
+            fs::create_dir_all(&dir)
+                .with_context(|| format!("failed to create {}", dir.display()))?;
+        }
+        fs::write(dir.join(format!("file{:06}.txt", i)), b"")?;
+    }
+    let entries = files / FILES_PER_HISTORY_ENTRY;
+    Ok(entries.min(history::MAX_HISTORY_LINES as usize))
+}
+
+fn write_history(history_path: &Path, tree: &Path, files: usize, entries: usize) -> Result<()> {
+    let mut history = Vec::new();
+    let stride = files / entries.max(1);
+    for (n, i) in (0..files).step_by(stride.max(1)).take(entries).enumerate() {
+        let path = tree.join(format!(
+            "d{:03}/s{:02}/file{:06}.txt",
+            i % 100,
+            (i / 100) % 10,
+            i
+        ));
+        history.extend_from_slice(format!("{}\t", n + 1).as_bytes());
+        history.extend_from_slice(path.as_os_str().as_bytes());
+        history.push(b'\n');
+    }
+    fs::write(history_path, history)
+        .with_context(|| format!("failed to write {}", history_path.display()))
+}
+
+fn make_fifo(path: &Path) -> Result<()> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    // SAFETY: c_path is a valid NUL-terminated string.
+    if unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) } != 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("failed to create {}", path.display()));
+    }
+    Ok(())
+}
+
+// The stub prints what fzf would for Enter on the first candidate: the empty
+// query, the empty key, and the selection.
+fn write_stub(stub: &Path, fifo: &Path) -> Result<()> {
+    let script = format!(
+        "#!/bin/sh\n\
+         IFS= read -r first\n\
+         printf x > '{}'\n\
+         cat > /dev/null\n\
+         printf '\\n\\n%s\\n' \"$first\"\n",
+        fifo.display()
+    );
+    fs::write(stub, script)?;
+    let mut permissions = fs::metadata(stub)?.permissions();
+    std::os::unix::fs::PermissionsExt::set_mode(&mut permissions, 0o755);
+    fs::set_permissions(stub, permissions)?;
+    Ok(())
+}
+
+fn median(durations: impl Iterator<Item = Duration>) -> Duration {
+    let mut durations: Vec<Duration> = durations.collect();
+    durations.sort();
+    durations
+        .get(durations.len() / 2)
+        .copied()
+        .unwrap_or_default()
+}
+
+fn format_ms(duration: Duration) -> String {
+    format!("{:.1}ms", duration.as_secs_f64() * 1000.0)
+}
@@ -0,0 +1,222 @@
+// The --cache mode keeps a copy of fd's output under the history dir, so that
+// big trees that rarely change don't need a full walk before anything shows
+// up. Each cache file belongs to one fd command line run from one cwd. Its
+// first line is a stamp made of that command line and the mtimes of the
+// search roots, and the rest is fd's output, byte for byte. A cache file is
+// used only if the stamp still matches and the file is younger than the TTL.
+//
+// The root mtimes are a coarse signal. They change when an entry is added to or
+// removed from a root itself, but not when something changes deeper down. The
+// TTL covers that, and so does the refresh in main.rs, which runs fd in the
+// background while fzf shows the cached listing and saves the result if fd
+// finishes in time.
+
+use anyhow::{Context, Result};
+use log::debug;
+use std::collections::hash_map::DefaultHasher;
+use std::ffi::OsString;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io::prelude::*;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+pub struct FdCache {
+    path: PathBuf,
+    stamp: Vec<u8>,
+    cached: Option<Vec<u8>>,
+}
+
+impl FdCache {
+    // Looks for a usable cache file for this fd command. A missing, stale, or
+    // unreadable cache isn't an error. In that case cached() returns None and
+    // the caller runs fd as usual, then calls store() with its output.
+    pub fn open(
+        cache_dir: &Path,
+        cwd: &Path,
+        fd_args: &[OsString],
+        roots: &[PathBuf],
+        ttl: Duration,
+    ) -> Result<FdCache> {
+        let mut stamp = Vec::new();
+        stamp.extend_from_slice(cwd.as_os_str().as_bytes());
+        for arg in fd_args {
+            stamp.push(0);
+            stamp.extend_from_slice(arg.as_bytes());
+        }
+        for root in roots {
+            let mtime = fs::metadata(root)
+                .and_then(|metadata| metadata.modified())
+                .with_context(|| format!("failed to stat {}", root.display()))?;
+            let since_epoch = mtime
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default();
+            stamp.push(0);
+            stamp.extend_from_slice(
+                format!(
+                    "{}.{:09}",
+                    since_epoch.as_secs(),
+                    since_epoch.subsec_nanos()
+                )
+                .as_bytes(),
+            );
+        }
+        // The stamp can't contain a newline, because the first newline ends
+        // it in the file.
+        for byte in &mut stamp {
+            if *byte == b'\n' {
+                *byte = 0;
+            }
+        }
+        // The file name only needs to be stable from one run to the next, and
+        // the stamp check below catches collisions. DefaultHasher::new() always
+        // starts from the same keys. If a new Rust version changes its output,
+        // old caches are just never found, and they expire like any other.
+        let mut hasher = DefaultHasher::new();
+        cwd.hash(&mut hasher);
+        fd_args.hash(&mut hasher);
+        let path = cache_dir.join(format!("{:016x}", hasher.finish()));
+        let cached = read_if_fresh(&path, &stamp, ttl);
+        debug!(
+            "fd cache {} at {}",
+            if cached.is_some() { "hit" } else { "miss" },
+            path.display()
+        );
+        Ok(FdCache {
+            path,
+            stamp,
+            cached,
+        })
+    }
+
+    pub fn cached(&self) -> Option<&[u8]> {
+        self.cached.as_deref()
+    }
+
+    // Writes a complete fd listing. The stamp is the one computed in open(),
+    // from before fd started, so anything that changes a root while fd is
+    // running makes the new file stale right away.
+    pub fn store(&self, fd_output: &[u8]) -> Result<()> {
+        let dir = self.path.parent().expect("cache file has a parent");
+        fs::create_dir_all(dir).with_context(|| format!("failed to create {}", dir.display()))?;
+        // Write to a temp file and rename it, so that a concurrent founder
+        // never reads half a listing.
+        let mut temp_path = self.path.clone().into_os_string();
+        temp_path.push(format!(".tmp.{}", std::process::id()));
+        let temp_path = PathBuf::from(temp_path);
+        let result = (|| {
+            let mut file = fs::File::create(&temp_path)?;
+            file.write_all(&self.stamp)?;
+            file.write_all(b"\n")?;
+            file.write_all(fd_output)?;
+            fs::rename(&temp_path, &self.path)
+        })();
+        if result.is_err() {
+            let _ = fs::remove_file(&temp_path);
+        }
+        result.with_context(|| format!("failed to write {}", self.path.display()))
+    }
+}
+
+fn read_if_fresh(path: &Path, stamp: &[u8], ttl: Duration) -> Option<Vec<u8>> {
+    let age = fs::metadata(path).ok()?.modified().ok()?.elapsed().ok()?;
+    if age > ttl {
+        return None;
+    }
+    let mut contents = fs::read(path).ok()?;
+    let newline = contents.iter().position(|&byte| byte == b'\n')?;
+    if &contents[..newline] != stamp {
+        return None;
+    }
+    contents.drain(..=newline);
+    Some(contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    const TTL: Duration = Duration::from_secs(60);
+
+    fn open(dir: &TempDir, fd_args: &[&str]) -> FdCache {
+        let fd_args: Vec<OsString> = fd_args.iter().map(OsString::from).collect();
+        let root = dir.path().join("root");
+        FdCache::open(
+            &dir.path().join("cache"),
+            &root,
+            &fd_args,
+            std::slice::from_ref(&root),
+            TTL,
+        )
+        .unwrap()
+    }
+
+    fn set_mtime(path: &Path, mtime: SystemTime) {
+        fs::File::open(path).unwrap().set_modified(mtime).unwrap();
+    }
+
+    fn setup() -> TempDir {
+        let dir = TempDir::new().unwrap();
+        fs::create_dir(dir.path().join("root")).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_store_and_reuse() {
+        let dir = setup();
+        let cache = open(&dir, &["--type=f"]);
+        assert!(cache.cached().is_none());
+        cache.store(b"a.txt\nb.txt\n").unwrap();
+        let cache = open(&dir, &["--type=f"]);
+        assert_eq!(cache.cached(), Some(&b"a.txt\nb.txt\n"[..]));
+        // An empty listing is still a listing.
+        cache.store(b"").unwrap();
+        assert_eq!(open(&dir, &["--type=f"]).cached(), Some(&b""[..]));
+    }
+
+    #[test]
+    fn test_other_fd_args_miss() {
+        let dir = setup();
+        open(&dir, &["--type=f"]).store(b"a.txt\n").unwrap();
+        assert!(open(&dir, &["--type=f", "--hidden"]).cached().is_none());
+        assert!(open(&dir, &["--type=f"]).cached().is_some());
+    }
+
+    #[test]
+    fn test_root_mtime_invalidates() {
+        let dir = setup();
+        let root = dir.path().join("root");
+        set_mtime(&root, SystemTime::UNIX_EPOCH + Duration::from_secs(1000));
+        open(&dir, &[]).store(b"a.txt\n").unwrap();
+        assert!(open(&dir, &[]).cached().is_some());
+        set_mtime(&root, SystemTime::UNIX_EPOCH + Duration::from_secs(2000));
+        assert!(open(&dir, &[]).cached().is_none());
+    }
+
+    #[test]
+    fn test_ttl_expires() {
+        let dir = setup();
+        let cache = open(&dir, &[]);
+        cache.store(b"a.txt\n").unwrap();
+        set_mtime(&cache.path, SystemTime::now() - 2 * TTL);
+        assert!(open(&dir, &[]).cached().is_none());
+    }
+
+    #[test]
+    fn test_corrupt_cache_files_miss() {
+        let dir = setup();
+        let cache = open(&dir, &[]);
+        // No stamp line at all.
+        fs::create_dir_all(cache.path.parent().unwrap()).unwrap();
+        fs::write(&cache.path, "a.txt").unwrap();
+        assert!(open(&dir, &[]).cached().is_none());
+        // A stamp that doesn't match, like a hash collision.
+        fs::write(&cache.path, "something else\na.txt\n").unwrap();
+        assert!(open(&dir, &[]).cached().is_none());
+        // Storing over it fixes it.
+        cache.store(b"b.txt\n").unwrap();
+        assert_eq!(open(&dir, &[]).cached(), Some(&b"b.txt\n"[..]));
+    }
+}
@@ -0,0 +1,1263 @@
+// Reading, writing, and compacting the file history. The functions that touch
+// a history file take its path as an argument. This module also knows the
+// default history dir and how --profile names the files in it, but it doesn't
+// remember any of that, or cache the history contents, or look at the Config.
+// main.rs does those things, and passes the paths in.
+
+use ahash::AHashMap;
+use anyhow::{bail, Context, Result};
+use log::{debug, info};
+use std::borrow::Cow;
+use std::collections::hash_map::Entry;
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicI32, AtomicUsize, Ordering};
+
+pub const MAX_HISTORY_LINES: u64 = 1000;
+
+// The history failures that callers might want to handle differently. main.rs
+// wraps these in its FounderError. Other is anything else that goes wrong
+// along the way.
+#[derive(Debug, thiserror::Error)]
+pub enum HistoryError {
+    #[error("failed to create history dir {}", .path.display())]
+    CreateHistoryDir {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    #[error("failed to read history {}", .path.display())]
+    ReadHistory {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    #[error("failed to write history {}", .path.display())]
+    WriteHistory {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+// The default history dir is the first of these that's set:
+//   1. $FOUNDER_HISTORY_DIR, used as is
+//   2. $XDG_DATA_HOME/founder, if $XDG_DATA_HOME is an absolute path
+//   3. founder in the platform's local data dir, which is ~/.local/share on
+//      Linux and ~/Library/Application Support on macOS
+// Empty variables are ignored. Returns the dir along with which of those
+// picked it, for --verbose, or None if none of them are available (a minimal
+// container with no HOME, for example). Nothing gets created here.
+pub fn default_history_dir() -> Option<(PathBuf, &'static str)> {
+    choose_history_dir(|name| env::var_os(name), dirs::data_local_dir())
+}
+
+fn choose_history_dir(
+    var: impl Fn(&str) -> Option<OsString>,
+    data_local_dir: Option<PathBuf>,
+) -> Option<(PathBuf, &'static str)> {
+    let non_empty_var = |name| var(name).filter(|value| !value.is_empty());
+    if let Some(dir) = non_empty_var("FOUNDER_HISTORY_DIR") {
+        Some((PathBuf::from(dir), "$FOUNDER_HISTORY_DIR"))
+    } else if let Some(dir) =
+        non_empty_var("XDG_DATA_HOME").filter(|dir| Path::new(dir).is_absolute())
+    {
+        Some((Path::new(&dir).join("founder"), "$XDG_DATA_HOME"))
+    } else {
+        data_local_dir.map(|dir| (dir.join("founder"), "the default data dir"))
+    }
+}
+
+pub fn create_history_dir(dir: &Path) -> Result<(), HistoryError> {
+    fs::create_dir_all(dir).map_err(|source| HistoryError::CreateHistoryDir {
+        path: dir.to_owned(),
+        source,
+    })
+}
+
+// Each --profile has its own file and query history, named like
+// file_history.work. Without a profile we use the original names.
+pub fn check_profile_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.starts_with('.') || name.contains('/') {
+        bail!("invalid profile name: {:?}", name);
+    }
+    Ok(())
+}
+
+pub fn history_file_name(base: &str, profile: Option<&str>) -> String {
+    match profile {
+        Some(profile) => format!("{}.{}", base, profile),
+        None => base.to_owned(),
+    }
+}
+
+// For --history-file. Relative paths are resolved against the cwd. The file
+// itself is created on the first write, but its parent directory needs to
+// exist already.
+pub fn history_file_override(path: &OsStr) -> Result<PathBuf> {
+    let absolute = PathBuf::from(absolute_path(path.as_bytes())?);
+    let parent = absolute.parent().unwrap_or_else(|| Path::new("/"));
+    if !parent.is_dir() {
+        bail!("history file directory doesn't exist: {}", parent.display());
+    }
+    Ok(absolute)
+}
+
+// A history file that doesn't exist yet is the same as an empty one.
+pub fn read_history_file(history_path: &Path) -> Result<Vec<u8>, HistoryError> {
+    match fs::read(history_path) {
+        Ok(bytes) => Ok(bytes),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(HistoryError::ReadHistory {
+            path: history_path.to_owned(),
+            source: e,
+        }),
+    }
+}
+
+// The history file is normally newline-delimited, which leaves no way to
+// record a path with a newline in it. With --history-null, a new history file
+// starts with a NUL byte, and then each line ends with a NUL instead. Nothing
+// in the newline format can start with a NUL, so that first byte is enough
+// to tell the formats apart, and files in the old format keep working. Once a
+// file is in the NUL format, it stays that way, with or without the flag.
+pub const NULL_FORMAT_HEADER: u8 = b'\0';
+
+// The separator for lines in a history file with these contents, and the
+// lines themselves, after the header if there is one.
+fn history_format(history_bytes: &[u8]) -> (u8, &[u8]) {
+    match history_bytes.split_first() {
+        Some((&NULL_FORMAT_HEADER, lines)) => (b'\0', lines),
+        _ => (b'\n', history_bytes),
+    }
+}
+
+// These lines do not include the terminating newline (or NUL).
+pub fn history_lines_from_most_recent(history_bytes: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let (separator, lines) = history_format(history_bytes);
+    lines
+        .rsplit(move |&byte| byte == separator)
+        .filter(|line| !line.is_empty())
+}
+
+// A line in the history file is either a bare absolute path (the original
+// format), or a Unix timestamp in seconds, a tab, and then an absolute path.
+// Compaction merges repeated selections of a path into one line, and then it
+// adds the number of selections as another field between the timestamp and
+// the path. (The timestamp can be empty there, if the merged lines were all
+// bare paths.) Paths are always absolute, so a field that starts with a slash
+// is never mistaken for a timestamp or a count. Lines without a count are
+// one selection each.
+//
+// With --branch-aware, there's also a field right before the path with an @
+// and the git branch the selection was made on, like "@main". Git doesn't
+// allow tabs in branch names, and the @ keeps a numeric branch name from
+// looking like a count. Older versions of founder read that field as a count
+// they can't parse, which counts as one selection, so they still handle a
+// single selection with a branch. A merged line with a branch confuses them.
+pub struct HistoryEntry<'a> {
+    pub line: &'a [u8],
+    pub path: &'a [u8],
+    pub timestamp: Option<u64>,
+    pub count: u64,
+    pub branch: Option<&'a [u8]>,
+}
+
+fn split_field(bytes: &[u8]) -> Option<(&[u8], &[u8])> {
+    if bytes.starts_with(b"/") {
+        return None;
+    }
+    let tab = bstr::ByteSlice::find_byte(bytes, b'\t')?;
+    Some((&bytes[..tab], &bytes[tab + 1..]))
+}
+
+fn parse_number(field: &[u8]) -> Option<u64> {
+    std::str::from_utf8(field).ok().and_then(|s| s.parse().ok())
+}
+
+pub fn parse_history_line(line: &[u8]) -> HistoryEntry<'_> {
+    let (timestamp, mut rest) = match split_field(line) {
+        Some((field, rest)) => (parse_number(field), rest),
+        None => (None, line),
+    };
+    let mut count = 1;
+    let mut branch = None;
+    while let Some((field, after)) = split_field(rest) {
+        match field.strip_prefix(b"@") {
+            Some(name) => branch = Some(name),
+            None => count = parse_number(field).unwrap_or(1),
+        }
+        rest = after;
+    }
+    HistoryEntry {
+        line,
+        path: rest,
+        timestamp,
+        count,
+        branch,
+    }
+}
+
+// Adds up the selection counts for each path, keyed like dedup_key.
+pub fn selection_counts(history_bytes: &[u8], ignore_case: bool) -> AHashMap<Cow<'_, [u8]>, u64> {
+    let mut counts = AHashMap::new();
+    for entry in history_entries_from_most_recent(history_bytes) {
+        *counts
+            .entry(dedup_key(ignore_case, entry.path))
+            .or_insert(0) += entry.count;
+    }
+    counts
+}
+
+pub fn history_entries_from_most_recent(
+    history_bytes: &[u8],
+) -> impl Iterator<Item = HistoryEntry<'_>> {
+    history_lines_from_most_recent(history_bytes).map(parse_history_line)
+}
+
+pub fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// Ctrl-C or SIGTERM in the middle of a history write could leave a half-written
+// line at the end of the history file, or a stray temp file from compaction.
+// While any HistoryWriteGuard is alive, those signals are deferred, and the
+// last guard to drop re-raises the signal. Outside of history writes, the
+// signals behave as usual.
+static HISTORY_WRITES_IN_PROGRESS: AtomicUsize = AtomicUsize::new(0);
+static PENDING_SIGNAL: AtomicI32 = AtomicI32::new(0);
+
+struct HistoryWriteGuard;
+
+impl HistoryWriteGuard {
+    fn new() -> Self {
+        HISTORY_WRITES_IN_PROGRESS.fetch_add(1, Ordering::SeqCst);
+        HistoryWriteGuard
+    }
+}
+
+impl Drop for HistoryWriteGuard {
+    fn drop(&mut self) {
+        // The handler sets PENDING_SIGNAL before checking the count, and we
+        // decrement the count before checking PENDING_SIGNAL, so at least one
+        // side sees the other and the signal isn't lost.
+        if HISTORY_WRITES_IN_PROGRESS.fetch_sub(1, Ordering::SeqCst) == 1 {
+            let signal = PENDING_SIGNAL.load(Ordering::SeqCst);
+            if signal != 0 {
+                let _ = signal_hook::low_level::emulate_default_handler(signal);
+            }
+        }
+    }
+}
+
+pub fn install_signal_handlers() -> Result<()> {
+    for &signal in &[signal_hook::consts::SIGINT, signal_hook::consts::SIGTERM] {
+        let action = move || {
+            PENDING_SIGNAL.store(signal, Ordering::SeqCst);
+            if HISTORY_WRITES_IN_PROGRESS.load(Ordering::SeqCst) == 0 {
+                let _ = signal_hook::low_level::emulate_default_handler(signal);
+            }
+        };
+        // SAFETY: The action only touches atomics and calls
+        // emulate_default_handler, which are async-signal-safe.
+        unsafe { signal_hook::low_level::register(signal, action) }
+            .context("failed to install signal handler")?;
+    }
+    Ok(())
+}
+
+// On case-insensitive filesystems (the macOS default), File.txt and file.txt
+// are the same file. With --ignore-case-paths, we dedup on a case-folded key,
+// and since we always keep the first occurrence we see, the displayed path has
+// the casing of the most recent one.
+pub fn dedup_key(ignore_case: bool, path: &[u8]) -> Cow<'_, [u8]> {
+    if ignore_case {
+        Cow::Owned(bstr::ByteSlice::to_lowercase(path))
+    } else {
+        Cow::Borrowed(path)
+    }
+}
+
+// What compact_history_file did, for the compact subcommand. When the history
+// is under the caps, nothing is rewritten, and the counts are equal.
+pub struct CompactSummary {
+    pub lines_before: u64,
+    pub lines_after: u64,
+    // Whether the history was rewritten in the NUL format.
+    pub converted: bool,
+}
+
+// The caller passes in the current contents of the history file, which it
+// has usually read already. A larger retain_fraction keeps more history at the
+// cost of compacting more often. ignore_case is the same as in dedup_key. If
+// max_bytes is set, that's a second cap alongside MAX_HISTORY_LINES, for
+// histories full of very long paths, and whichever cap is hit first triggers
+// compaction. With keep_duplicates, repeated paths aren't merged, so every
+// line counts toward the caps, and the history covers fewer distinct paths.
+// With null_format, a newline-format file gets rewritten in the NUL format,
+// even if it's under the caps. A NUL-format file is never converted back.
+pub fn compact_history_file(
+    history_path: &Path,
+    history_bytes: &[u8],
+    retain_fraction: f64,
+    ignore_case: bool,
+    max_bytes: Option<u64>,
+    keep_duplicates: bool,
+    null_format: bool,
+) -> Result<CompactSummary, HistoryError> {
+    // Iterate over all the history lines, starting with the most recent, and
+    // collect the first unique occurrence of each path into a vector, adding
+    // up the selection counts of the later occurrences. A path selected only
+    // once keeps its whole line, and a merged line keeps the most recent
+    // timestamp. Selections on different --branch-aware branches (or on no
+    // branch) stay on separate lines, so that compaction doesn't take a path
+    // away from a branch it was selected on. We use ahash for the map.
+    // `founder bench --compaction` compares it with the default SipHash on a
+    // large history.
+    let mut total_lines: u64 = 0;
+    let mut unique_indexes: AHashMap<_, usize> = AHashMap::new();
+    let mut ordered_unique_entries: Vec<(HistoryEntry, u64)> = Vec::new();
+    for entry in history_entries_from_most_recent(history_bytes) {
+        total_lines += 1;
+        if keep_duplicates {
+            let count = entry.count;
+            ordered_unique_entries.push((entry, count));
+            continue;
+        }
+        match unique_indexes.entry((entry.branch, dedup_key(ignore_case, entry.path))) {
+            Entry::Occupied(index) => ordered_unique_entries[*index.get()].1 += entry.count,
+            Entry::Vacant(vacant) => {
+                vacant.insert(ordered_unique_entries.len());
+                let count = entry.count;
+                ordered_unique_entries.push((entry, count));
+            }
+        }
+    }
+    // If the history file does not need to be truncated, short-circuit.
+    let over_max_bytes = max_bytes.is_some_and(|max| history_bytes.len() as u64 > max);
+    let (current_separator, _) = history_format(history_bytes);
+    let converting = null_format && current_separator == b'\n';
+    let separator = if null_format {
+        b'\0'
+    } else {
+        current_separator
+    };
+    let over_caps = total_lines > MAX_HISTORY_LINES || over_max_bytes;
+    if !over_caps && !converting {
+        debug!(
+            "history has {} lines and {} bytes, not compacting",
+            total_lines,
+            history_bytes.len()
+        );
+        return Ok(CompactSummary {
+            lines_before: total_lines,
+            lines_after: total_lines,
+            converted: false,
+        });
+    }
+    // Retain only a fraction of the maximum number of lines, half by default.
+    // (Though pruning duplicates above might already have brought us below
+    // that.) This means that we'll go a long time between compactions, rather
+    // than compacting all the time when the history file is full of unique
+    // entries.
+    let mut ordered_unique_lines: Vec<Cow<[u8]>> = ordered_unique_entries
+        .iter()
+        .map(|(entry, count)| {
+            if *count == entry.count {
+                return Cow::Borrowed(entry.line);
+            }
+            let mut line = match entry.timestamp {
+                Some(timestamp) => format!("{}\t{}\t", timestamp, count),
+                None => format!("\t{}\t", count),
+            }
+            .into_bytes();
+            // Every line merged into this one has the same branch.
+            if let Some(branch) = entry.branch {
+                line.push(b'@');
+                line.extend_from_slice(branch);
+                line.push(b'\t');
+            }
+            line.extend_from_slice(entry.path);
+            Cow::Owned(line)
+        })
+        .collect();
+    // Converting a history that's under the caps keeps all of it.
+    if over_caps {
+        let retained_lines = (MAX_HISTORY_LINES as f64 * retain_fraction) as usize;
+        ordered_unique_lines.truncate(retained_lines.max(1));
+    }
+    // The byte cap works the same way. Keep the most recent lines that fit in
+    // the retained fraction of max_bytes, counting their newlines, and drop
+    // the older ones. We always keep at least one line, even if it's longer
+    // than the cap by itself.
+    if let Some(max_bytes) = max_bytes.filter(|_| over_caps) {
+        let retained_bytes = (max_bytes as f64 * retain_fraction) as u64;
+        let mut kept_bytes = 0;
+        let kept_lines = ordered_unique_lines
+            .iter()
+            .take_while(|line| {
+                kept_bytes += line.len() as u64 + 1;
+                kept_bytes <= retained_bytes
+            })
+            .count();
+        ordered_unique_lines.truncate(kept_lines.max(1));
+    }
+    // Write the remaining lines to a temporary file. Once the lines are
+    // written, we'll swap it with the real history file. Note that this
+    // temporary file must be on the same filesystem as the real one, so a
+    // standard temp file in /tmp doesn't work here.
+    //
+    // The temp file name includes our PID, so that concurrent compactions
+    // don't collide, and a file left behind by an earlier run (say, one that
+    // was SIGKILLed) can't make compaction fail forever. Only a dead process
+    // could have left a file with our PID, so it's safe to remove one. The
+    // suffix is appended rather than replacing the extension, so that
+    // profiles like file_history.work don't share a temp file name. We don't
+    // touch the plain .tmp file that older versions used. With --history-file,
+    // that name could belong to something else.
+    let _guard = HistoryWriteGuard::new();
+    let mut temp_file_path = history_path.as_os_str().to_owned();
+    temp_file_path.push(format!(".tmp.{}", std::process::id()));
+    let temp_file_path = PathBuf::from(temp_file_path);
+    let _ = fs::remove_file(&temp_file_path);
+    let write_error = |source| HistoryError::WriteHistory {
+        path: history_path.to_owned(),
+        source,
+    };
+    let temp_file = open_history_file(
+        &temp_file_path,
+        fs::OpenOptions::new().write(true).create_new(true), // error if the file already exists
+    )?;
+    let mut temp_file_writer = io::BufWriter::new(temp_file);
+    if separator == b'\0' {
+        temp_file_writer
+            .write_all(&[NULL_FORMAT_HEADER])
+            .map_err(write_error)?;
+    }
+    // Note that lines in the history file are oldest-to-newest, which is the
+    // opposite of what's in our vector here, so we reverse it.
+    for line in ordered_unique_lines.iter().rev() {
+        temp_file_writer.write_all(line).map_err(write_error)?;
+        temp_file_writer
+            .write_all(&[separator])
+            .map_err(write_error)?;
+    }
+    temp_file_writer.flush().map_err(write_error)?;
+    drop(temp_file_writer);
+    // Swap the new history file into place.
+    fs::rename(&temp_file_path, history_path).map_err(write_error)?;
+    info!(
+        "compacted {} from {} lines to {}",
+        history_path.display(),
+        total_lines,
+        ordered_unique_lines.len()
+    );
+    Ok(CompactSummary {
+        lines_before: total_lines,
+        lines_after: ordered_unique_lines.len() as u64,
+        converted: converting,
+    })
+}
+
+// The history dir can get deleted while we're running, by an aggressive cleanup
+// script for example. If opening a file for writing fails with NotFound,
+// recreate its directory, like main.rs does on startup, and try once more.
+fn open_history_file(path: &Path, options: &fs::OpenOptions) -> Result<fs::File, HistoryError> {
+    let write_error = |source| HistoryError::WriteHistory {
+        path: path.to_owned(),
+        source,
+    };
+    match options.open(path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            create_history_dir(path.parent().unwrap_or_else(|| Path::new("/")))?;
+            options.open(path).map_err(write_error)
+        }
+        result => result.map_err(write_error),
+    }
+}
+
+// This is how paths normally get into the history. What PathAbs does, for the
+// record:
+//   - A relative path is joined to the cwd. The cwd is canonicalized first, so
+//     if you're in a symlinked directory, the entry uses its real path.
+//   - . components are dropped.
+//   - .. components pop the previous component lexically, without looking at
+//     the filesystem. So if /tmp/link points to /tmp/dir/sub, then
+//     /tmp/link/../a.txt becomes /tmp/a.txt, not /tmp/dir/a.txt.
+//   - A .. that would go above the root is an error rather than staying at /.
+//   - Repeated slashes collapse, and a trailing slash is dropped.
+// expand_selection doesn't normalize anything. It only expands ~, so what
+// comes back out of fzf is whatever display_path made of the entry.
+pub fn absolute_path(path: &[u8]) -> Result<OsString> {
+    let path_osstr = OsStr::from_bytes(path);
+    // Note that we don't use std::fs::canonicalize here. That fails for files
+    // that don't exist. (A common example is "vim foo.txt". That file doesn't
+    // exist until you save it, but we want to add it to history immediately.)
+    // It's also better not to resolve symbolic links, but to allow different
+    // paths to the same file to exist separately in history.
+    let absolute = path_abs::PathAbs::new(path_osstr)?;
+    // PathAbs makes "." (and "./", "a/..", and so on) into an empty path,
+    // rather than the cwd, so `founder add .` would've recorded "".
+    if absolute.as_path().as_os_str().is_empty() {
+        let cwd = path_abs::PathAbs::new(env::current_dir()?)?;
+        return Ok(cwd.as_path().as_os_str().to_owned());
+    }
+    Ok(absolute.as_path().as_os_str().to_owned())
+}
+
+// With --no-normalize, a relative path is joined to the cwd and otherwise kept
+// as given, . and .. included, and an absolute path is kept exactly. Entries
+// like that don't dedup against the same file spelled differently, by fd for
+// example, so this is only for callers who need the path they passed in.
+fn verbatim_absolute_path(path: &[u8]) -> Result<OsString> {
+    let path = Path::new(OsStr::from_bytes(path));
+    if path.is_absolute() {
+        Ok(path.as_os_str().to_owned())
+    } else {
+        Ok(env::current_dir()?.join(path).into_os_string())
+    }
+}
+
+// Shell integrations sometimes pass an empty string, and "" would otherwise
+// get absolutified into the cwd. Whitespace-only paths are almost always the
+// same sort of mistake, but a file with a name like that can exist, and then
+// we record it.
+pub fn is_bogus_path(path: &[u8]) -> bool {
+    path.iter().all(u8::is_ascii_whitespace) && !Path::new(OsStr::from_bytes(path)).exists()
+}
+
+// branch is the git branch to tag the entry with, for --branch-aware. With
+// null_format, a new history file gets the NUL format. An existing file keeps
+// its format, and compaction converts it.
+pub fn add_path_to_history(
+    history_path: &Path,
+    path: &[u8],
+    resolve_symlinks: bool,
+    normalize: bool,
+    branch: Option<&str>,
+    null_format: bool,
+) -> Result<()> {
+    if is_bogus_path(path) {
+        bail!(
+            "not recording an empty or blank path: {:?}",
+            OsStr::from_bytes(path)
+        );
+    }
+    add_paths_to_history(
+        history_path,
+        &[path],
+        resolve_symlinks,
+        normalize,
+        branch,
+        null_format,
+    )?;
+    Ok(())
+}
+
+// Like add_path_to_history, but with a single open and write for all the
+// paths. Returns how many were recorded.
+pub fn add_paths_to_history(
+    history_path: &Path,
+    paths: &[&[u8]],
+    resolve_symlinks: bool,
+    normalize: bool,
+    branch: Option<&str>,
+    null_format: bool,
+) -> Result<usize> {
+    let _guard = HistoryWriteGuard::new();
+    let mut history_file = open_history_file(
+        history_path,
+        fs::OpenOptions::new().read(true).append(true).create(true),
+    )?;
+    // Check the format through the same file descriptor we're about to append
+    // to, so that a compaction renaming a new file into place can't change
+    // the format out from under us. A new or empty file gets a header if it's
+    // going to be in the NUL format.
+    let mut first_byte = [0];
+    let mut history_lines = Vec::new();
+    let separator = match history_file.read(&mut first_byte)? {
+        0 if null_format => {
+            history_lines.push(NULL_FORMAT_HEADER);
+            b'\0'
+        }
+        0 => b'\n',
+        _ => history_format(&first_byte).0,
+    };
+    let now = unix_now();
+    let mut recorded = 0;
+    for &path in paths {
+        match history_line(path, now, resolve_symlinks, normalize, branch, separator)? {
+            Some(line) => {
+                history_lines.extend_from_slice(&line);
+                recorded += 1;
+            }
+            None => {
+                eprintln!("founder: not recording a path with a newline in it (see --history-null)")
+            }
+        }
+    }
+    if recorded == 0 {
+        return Ok(0);
+    }
+    history_file.write_all(&history_lines)?;
+    Ok(recorded)
+}
+
+fn history_line(
+    path: &[u8],
+    now: u64,
+    resolve_symlinks: bool,
+    normalize: bool,
+    branch: Option<&str>,
+    separator: u8,
+) -> Result<Option<Vec<u8>>> {
+    let absolute_path = if resolve_symlinks {
+        // With --resolve-symlinks, different paths to the same file collapse
+        // into one history entry. Files that don't exist yet can't be
+        // canonicalized, so those fall back to the usual absolute path.
+        match fs::canonicalize(OsStr::from_bytes(path)) {
+            Ok(canonical_path) => canonical_path.into_os_string(),
+            Err(_) => absolute_path(path)?,
+        }
+    } else if normalize {
+        absolute_path(path)?
+    } else {
+        verbatim_absolute_path(path)?
+    };
+    // In a newline-delimited file, a path with a newline in it (possible with
+    // --null) can't be recorded. No path contains a NUL.
+    if absolute_path.as_bytes().contains(&separator) {
+        return Ok(None);
+    }
+    // Prefix the path with the current time, and add the separator at the end.
+    // We build the whole line first, so that it goes out in a single write.
+    let mut history_line = OsString::from(format!("{}\t", now));
+    if let Some(branch) = branch {
+        history_line.push(format!("@{}\t", branch));
+    }
+    history_line.push(&absolute_path);
+    let mut history_line = history_line.into_vec();
+    history_line.push(separator);
+    Ok(Some(history_line))
+}
+
+// Selection log lines are "<unix secs>\t<query>\t<absolute path>". Tabs and
+// newlines in the query become spaces, so the path is everything after the
+// second tab. Paths with newlines are skipped, as in the history file.
+pub fn add_to_selection_log(
+    log_path: &Path,
+    path: &[u8],
+    query: &[u8],
+    resolve_symlinks: bool,
+    normalize: bool,
+) -> Result<()> {
+    let mut log_line =
+        match history_line(path, unix_now(), resolve_symlinks, normalize, None, b'\n')? {
+            Some(line) => line,
+            None => return Ok(()),
+        };
+    let mut query_field: Vec<u8> = query
+        .iter()
+        .map(|&byte| match byte {
+            b'\t' | b'\n' => b' ',
+            _ => byte,
+        })
+        .collect();
+    query_field.push(b'\t');
+    let after_timestamp = log_line.iter().position(|&byte| byte == b'\t').unwrap() + 1;
+    log_line.splice(after_timestamp..after_timestamp, query_field);
+    let _guard = HistoryWriteGuard::new();
+    let mut log_file =
+        open_history_file(log_path, fs::OpenOptions::new().append(true).create(true))?;
+    log_file.write_all(&log_line)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn compact(history_path: &Path) -> CompactSummary {
+        let bytes = read_history_file(history_path).unwrap();
+        compact_history_file(history_path, &bytes, 0.5, false, None, false, false).unwrap()
+    }
+
+    fn lines(history_path: &Path) -> Vec<String> {
+        fs::read_to_string(history_path)
+            .unwrap()
+            .lines()
+            .map(String::from)
+            .collect()
+    }
+
+    #[test]
+    fn test_compaction_above_the_cap() {
+        let dir = TempDir::new().unwrap();
+        let history_path = dir.path().join("file_history");
+        let mut history = String::new();
+        for i in 0..MAX_HISTORY_LINES + 1 {
+            history.push_str(&format!("{}\t/file{}\n", i, i));
+        }
+        fs::write(&history_path, &history).unwrap();
+        let summary = compact(&history_path);
+        assert_eq!(summary.lines_before, MAX_HISTORY_LINES + 1);
+        assert_eq!(summary.lines_after, MAX_HISTORY_LINES / 2);
+        let lines = lines(&history_path);
+        assert_eq!(lines.len() as u64, MAX_HISTORY_LINES / 2);
+        // The most recent entries are the ones that survive.
+        assert_eq!(lines.last().unwrap(), "1000\t/file1000");
+    }
+
+    #[test]
+    fn test_no_compaction_at_the_cap() {
+        let dir = TempDir::new().unwrap();
+        let history_path = dir.path().join("file_history");
+        let mut history = String::new();
+        for i in 0..MAX_HISTORY_LINES {
+            history.push_str(&format!("{}\t/same\n", i));
+        }
+        fs::write(&history_path, &history).unwrap();
+        let summary = compact(&history_path);
+        assert_eq!(summary.lines_before, MAX_HISTORY_LINES);
+        assert_eq!(summary.lines_after, MAX_HISTORY_LINES);
+        assert_eq!(fs::read_to_string(&history_path).unwrap(), history);
+    }
+
+    #[test]
+    fn test_compaction_keeps_the_most_recent_occurrence() {
+        let dir = TempDir::new().unwrap();
+        let history_path = dir.path().join("file_history");
+        let mut history = String::new();
+        // /a and /b alternate, with /a last, and /c is selected once at the
+        // start. That's over the cap before dedup.
+        history.push_str("1\t/c\n");
+        for i in 2..MAX_HISTORY_LINES + 2 {
+            let path = if i % 2 == 0 { "/b" } else { "/a" };
+            history.push_str(&format!("{}\t{}\n", i, path));
+        }
+        fs::write(&history_path, &history).unwrap();
+        let summary = compact(&history_path);
+        assert_eq!(summary.lines_after, 3);
+        // Oldest to newest, with merged lines keeping the most recent
+        // timestamp and a count of all their selections.
+        assert_eq!(
+            lines(&history_path),
+            ["1\t/c", "1000\t500\t/b", "1001\t500\t/a"]
+        );
+        // Entries read back most recent first.
+        let bytes = read_history_file(&history_path).unwrap();
+        let paths: Vec<&[u8]> = history_entries_from_most_recent(&bytes)
+            .map(|entry| entry.path)
+            .collect();
+        assert_eq!(paths, [&b"/a"[..], b"/b", b"/c"]);
+    }
+
+    fn compact_to_bytes(history_path: &Path, max_bytes: u64) -> CompactSummary {
+        let bytes = read_history_file(history_path).unwrap();
+        compact_history_file(
+            history_path,
+            &bytes,
+            0.5,
+            false,
+            Some(max_bytes),
+            false,
+            false,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_compaction_above_the_byte_cap() {
+        let dir = TempDir::new().unwrap();
+        let history_path = dir.path().join("file_history");
+        let mut history = String::new();
+        for i in 0..10 {
+            history.push_str(&format!("{}\t/file{}\n", i, i));
+        }
+        fs::write(&history_path, &history).unwrap();
+        // Under the cap, nothing happens.
+        assert_eq!(compact_to_bytes(&history_path, 1000).lines_after, 10);
+        assert_eq!(fs::read_to_string(&history_path).unwrap(), history);
+        // Each line is 9 bytes with its newline, so half of 60 bytes keeps
+        // the newest three whole lines. The oldest ones go.
+        assert_eq!(compact_to_bytes(&history_path, 60).lines_after, 3);
+        assert_eq!(
+            fs::read_to_string(&history_path).unwrap(),
+            "7\t/file7\n8\t/file8\n9\t/file9\n"
+        );
+    }
+
+    #[test]
+    fn test_compaction_keeps_the_newest_entry_over_the_byte_cap() {
+        let dir = TempDir::new().unwrap();
+        let history_path = dir.path().join("file_history");
+        let long_path = format!("/{}", "x".repeat(100));
+        fs::write(&history_path, format!("1\t/short\n2\t{}\n", long_path)).unwrap();
+        assert_eq!(compact_to_bytes(&history_path, 50).lines_after, 1);
+        assert_eq!(lines(&history_path), [format!("2\t{}", long_path)]);
+    }
+
+    #[test]
+    fn test_compaction_recovers_from_a_leftover_temp_file() {
+        let dir = TempDir::new().unwrap();
+        let history_path = dir.path().join("paths.txt");
+        let mut history = String::new();
+        for i in 0..MAX_HISTORY_LINES + 1 {
+            history.push_str(&format!("{}\t/file{}\n", i, i));
+        }
+        fs::write(&history_path, &history).unwrap();
+        // An interrupted compaction with our PID, as if the PID got reused,
+        // and an unrelated file that happens to have the old temp name.
+        let leftover = dir
+            .path()
+            .join(format!("paths.txt.tmp.{}", std::process::id()));
+        fs::write(&leftover, "partial").unwrap();
+        let unrelated = dir.path().join("paths.tmp");
+        fs::write(&unrelated, "notes").unwrap();
+        assert_eq!(compact(&history_path).lines_after, MAX_HISTORY_LINES / 2);
+        assert_eq!(lines(&history_path).len() as u64, MAX_HISTORY_LINES / 2);
+        assert!(!leftover.exists());
+        assert_eq!(fs::read_to_string(&unrelated).unwrap(), "notes");
+    }
+
+    #[test]
+    fn test_writes_are_oldest_to_newest() {
+        let dir = TempDir::new().unwrap();
+        let history_path = dir.path().join("file_history");
+        add_path_to_history(&history_path, b"/first", false, true, None, false).unwrap();
+        add_paths_to_history(
+            &history_path,
+            &[b"/second", b"/third"],
+            false,
+            true,
+            None,
+            false,
+        )
+        .unwrap();
+        let paths: Vec<String> = lines(&history_path)
+            .iter()
+            .map(|line| line.split('\t').nth(1).unwrap().to_owned())
+            .collect();
+        assert_eq!(paths, ["/first", "/second", "/third"]);
+    }
+
+    #[test]
+    fn test_missing_history_file() {
+        let dir = TempDir::new().unwrap();
+        let history_path = dir.path().join("file_history");
+        assert!(read_history_file(&history_path).unwrap().is_empty());
+        let summary = compact(&history_path);
+        assert_eq!(summary.lines_before, 0);
+        assert_eq!(summary.lines_after, 0);
+        // Compacting nothing doesn't create the file, but adding to it does.
+        assert!(!history_path.exists());
+        add_path_to_history(&history_path, b"/a", false, true, None, false).unwrap();
+        assert_eq!(lines(&history_path).len(), 1);
+    }
+
+    #[test]
+    fn test_empty_history_file() {
+        let dir = TempDir::new().unwrap();
+        let history_path = dir.path().join("file_history");
+        fs::write(&history_path, "").unwrap();
+        assert!(read_history_file(&history_path).unwrap().is_empty());
+        assert_eq!(compact(&history_path).lines_before, 0);
+        assert_eq!(history_lines_from_most_recent(b"").count(), 0);
+        // Blank lines aren't entries.
+        assert_eq!(history_lines_from_most_recent(b"\n\n").count(), 0);
+    }
+
+    #[test]
+    fn test_parse_history_lines() {
+        // The original format is a bare path.
+        let entry = parse_history_line(b"/a/b.txt");
+        assert_eq!(entry.path, b"/a/b.txt");
+        assert_eq!((entry.timestamp, entry.count), (None, 1));
+        // Then a timestamp, and then a count for merged lines.
+        let entry = parse_history_line(b"100\t/a/b.txt");
+        assert_eq!(entry.path, b"/a/b.txt");
+        assert_eq!((entry.timestamp, entry.count), (Some(100), 1));
+        let entry = parse_history_line(b"100\t3\t/a/b.txt");
+        assert_eq!(entry.path, b"/a/b.txt");
+        assert_eq!((entry.timestamp, entry.count), (Some(100), 3));
+        // Merged bare paths have an empty timestamp.
+        let entry = parse_history_line(b"\t3\t/a/b.txt");
+        assert_eq!((entry.timestamp, entry.count), (None, 3));
+        // Paths can have tabs in them.
+        let entry = parse_history_line(b"100\t/a\t/b.txt");
+        assert_eq!(entry.path, b"/a\t/b.txt");
+        // A count we can't read is one selection.
+        let entry = parse_history_line(b"100\tmany\t/a/b.txt");
+        assert_eq!((entry.path, entry.count), (&b"/a/b.txt"[..], 1));
+        assert!(entry.branch.is_none());
+    }
+
+    #[test]
+    fn test_selection_counts() {
+        let history = b"/a\n1\t/b\n2\t4\t/a\n3\t/A\n";
+        let counts = selection_counts(history, false);
+        assert_eq!(counts[&b"/a"[..]], 5);
+        assert_eq!(counts[&b"/b"[..]], 1);
+        assert_eq!(counts[&b"/A"[..]], 1);
+        let counts = selection_counts(history, true);
+        assert_eq!(counts[&b"/a"[..]], 6);
+        assert_eq!(counts.len(), 2);
+    }
+
+    #[test]
+    fn test_history_line() {
+        let line = history_line(b"/a/b.txt", 100, false, true, None, b'\n').unwrap();
+        assert_eq!(line.unwrap(), b"100\t/a/b.txt\n");
+        let line = history_line(b"/a/b.txt", 100, false, true, None, b'\0').unwrap();
+        assert_eq!(line.unwrap(), b"100\t/a/b.txt\0");
+        let line = history_line(b"/a\nb", 100, false, true, None, b'\n').unwrap();
+        assert!(line.is_none());
+    }
+
+    #[test]
+    fn test_parse_branch_lines() {
+        let entry = parse_history_line(b"100\t@main\t/a/b.txt");
+        assert_eq!(entry.path, b"/a/b.txt");
+        assert_eq!(entry.branch, Some(&b"main"[..]));
+        assert_eq!((entry.timestamp, entry.count), (Some(100), 1));
+        // Merged lines put the count before the branch.
+        let entry = parse_history_line(b"100\t3\t@feature/x\t/a/b.txt");
+        assert_eq!(entry.branch, Some(&b"feature/x"[..]));
+        assert_eq!(entry.count, 3);
+        // The @ keeps a numeric branch from being read as a count.
+        let entry = parse_history_line(b"100\t@123\t/a/b.txt");
+        assert_eq!((entry.branch, entry.count), (Some(&b"123"[..]), 1));
+        // No branch field means no branch.
+        let entry = parse_history_line(b"100\t3\t/a/b.txt");
+        assert_eq!((entry.branch, entry.count), (None, 3));
+    }
+
+    #[test]
+    fn test_compaction_keeps_branches_separate() {
+        let dir = TempDir::new().unwrap();
+        let history_path = dir.path().join("file_history");
+        let mut history = String::new();
+        for i in 0..MAX_HISTORY_LINES + 1 {
+            let branch = match i % 3 {
+                0 => "@main\t",
+                1 => "@feature\t",
+                _ => "",
+            };
+            history.push_str(&format!("{}\t{}/a\n", i, branch));
+        }
+        fs::write(&history_path, &history).unwrap();
+        assert_eq!(compact(&history_path).lines_after, 3);
+        assert_eq!(
+            lines(&history_path),
+            [
+                "998\t333\t/a",
+                "999\t334\t@main\t/a",
+                "1000\t334\t@feature\t/a"
+            ]
+        );
+        // All the selections of /a still add up.
+        let bytes = read_history_file(&history_path).unwrap();
+        assert_eq!(selection_counts(&bytes, false)[&b"/a"[..]], 1001);
+    }
+
+    fn compact_to(history_path: &Path, null_format: bool) -> CompactSummary {
+        let bytes = read_history_file(history_path).unwrap();
+        compact_history_file(history_path, &bytes, 0.5, false, None, false, null_format).unwrap()
+    }
+
+    #[test]
+    fn test_compaction_converts_to_the_null_format() {
+        let dir = TempDir::new().unwrap();
+        let history_path = dir.path().join("file_history");
+        fs::write(&history_path, "1\t/a\n2\t/b\n").unwrap();
+        // Under the caps, compaction only runs to convert.
+        let summary = compact_to(&history_path, true);
+        assert!(summary.converted);
+        assert_eq!(summary.lines_after, 2);
+        assert_eq!(fs::read(&history_path).unwrap(), b"\x001\t/a\x002\t/b\0");
+        // Once it's converted, there's nothing left to do.
+        assert!(!compact_to(&history_path, true).converted);
+    }
+
+    #[test]
+    fn test_reading_the_null_format_without_the_flag() {
+        let dir = TempDir::new().unwrap();
+        let history_path = dir.path().join("file_history");
+        fs::write(&history_path, b"\x001\t/a\x002\t/b\nc\0").unwrap();
+        let bytes = read_history_file(&history_path).unwrap();
+        let paths: Vec<&[u8]> = history_entries_from_most_recent(&bytes)
+            .map(|entry| entry.path)
+            .collect();
+        assert_eq!(paths, [&b"/b\nc"[..], b"/a"]);
+        // Appending without --history-null keeps the file's format.
+        add_path_to_history(&history_path, b"/d", false, true, None, false).unwrap();
+        let bytes = read_history_file(&history_path).unwrap();
+        assert!(bytes.starts_with(b"\x001\t/a\x002\t/b\nc\0"));
+        assert!(bytes.ends_with(b"\t/d\0"));
+    }
+
+    #[test]
+    fn test_compaction_keeps_the_null_format_without_the_flag() {
+        let dir = TempDir::new().unwrap();
+        let history_path = dir.path().join("file_history");
+        let mut history = vec![NULL_FORMAT_HEADER];
+        for i in 0..MAX_HISTORY_LINES + 1 {
+            history.extend_from_slice(format!("{}\t/file{}\0", i, i).as_bytes());
+        }
+        fs::write(&history_path, &history).unwrap();
+        let summary = compact_to(&history_path, false);
+        assert!(!summary.converted);
+        assert_eq!(summary.lines_after, MAX_HISTORY_LINES / 2);
+        let bytes = fs::read(&history_path).unwrap();
+        assert_eq!(bytes[0], NULL_FORMAT_HEADER);
+        assert!(!bytes.contains(&b'\n'));
+        assert!(bytes.ends_with(b"1000\t/file1000\0"));
+    }
+
+    #[test]
+    fn test_a_path_with_a_newline_round_trips() {
+        let dir = TempDir::new().unwrap();
+        let history_path = dir.path().join("file_history");
+        add_path_to_history(&history_path, b"/two\nlines.txt", false, true, None, true).unwrap();
+        let bytes = read_history_file(&history_path).unwrap();
+        let entries: Vec<HistoryEntry> = history_entries_from_most_recent(&bytes).collect();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].path, b"/two\nlines.txt");
+        // Compaction keeps the path in one piece.
+        let mut history = bytes.clone();
+        for i in 0..MAX_HISTORY_LINES {
+            history.extend_from_slice(format!("{}\t/file{}\0", i, i).as_bytes());
+        }
+        history.extend_from_slice(b"9999\t/two\nlines.txt\0");
+        fs::write(&history_path, &history).unwrap();
+        compact_to(&history_path, true);
+        let bytes = read_history_file(&history_path).unwrap();
+        let newest = history_entries_from_most_recent(&bytes).next().unwrap();
+        assert_eq!(newest.path, b"/two\nlines.txt");
+        assert_eq!(newest.count, 2);
+        // A newline-delimited history can't hold it, so it isn't recorded.
+        let newline_path = dir.path().join("newline_history");
+        add_path_to_history(&newline_path, b"/two\nlines.txt", false, true, None, false).unwrap();
+        assert!(read_history_file(&newline_path).unwrap().is_empty());
+    }
+
+    fn absolute(path: &str) -> Result<PathBuf> {
+        absolute_path(path.as_bytes()).map(PathBuf::from)
+    }
+
+    fn verbatim(path: &str) -> OsString {
+        verbatim_absolute_path(path.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn test_absolute_path() {
+        let cwd = fs::canonicalize(env::current_dir().unwrap()).unwrap();
+        assert_eq!(absolute("./sub/../c.txt").unwrap(), cwd.join("c.txt"));
+        assert_eq!(absolute("sub//b.txt/").unwrap(), cwd.join("sub/b.txt"));
+        assert_eq!(absolute(".").unwrap(), cwd);
+        assert_eq!(absolute("./").unwrap(), cwd);
+        assert_eq!(absolute("sub/..").unwrap(), cwd);
+        assert_eq!(absolute("/a/./b/../c").unwrap(), Path::new("/a/c"));
+        assert_eq!(absolute("/").unwrap(), Path::new("/"));
+        assert!(absolute("/..").is_err());
+        assert!(absolute("/a/../../b").is_err());
+        let too_many_dots = "../".repeat(cwd.components().count());
+        assert!(absolute(&too_many_dots).is_err());
+    }
+
+    #[test]
+    fn test_absolute_path_doesnt_follow_symlinks() {
+        let dir = TempDir::new().unwrap();
+        let dir_path = fs::canonicalize(dir.path()).unwrap();
+        fs::create_dir_all(dir_path.join("real/sub")).unwrap();
+        std::os::unix::fs::symlink(dir_path.join("real/sub"), dir_path.join("link")).unwrap();
+        // .. pops the link itself, not the directory it points into.
+        let through_link = dir_path.join("link/../a.txt");
+        assert_eq!(
+            absolute(through_link.to_str().unwrap()).unwrap(),
+            dir_path.join("a.txt")
+        );
+        assert_eq!(
+            absolute(dir_path.join("link/b.txt").to_str().unwrap()).unwrap(),
+            dir_path.join("link/b.txt")
+        );
+    }
+
+    #[test]
+    fn test_verbatim_absolute_path() {
+        let cwd = env::current_dir().unwrap();
+        let joined = |path: &str| OsString::from(format!("{}/{}", cwd.display(), path));
+        assert_eq!(verbatim("./sub/../c.txt"), joined("./sub/../c.txt"));
+        assert_eq!(verbatim("sub//b.txt/"), joined("sub//b.txt/"));
+        assert_eq!(verbatim("/tmp/link/../a.txt"), "/tmp/link/../a.txt");
+        // Nothing is resolved, so going above the root isn't an error here.
+        assert_eq!(verbatim("/../a.txt"), "/../a.txt");
+    }
+
+    #[test]
+    fn test_recreates_a_deleted_history_dir() {
+        let dir = TempDir::new().unwrap();
+        let history_dir = dir.path().join("founder");
+        let history_path = history_dir.join("file_history");
+        fs::create_dir(&history_dir).unwrap();
+        add_path_to_history(&history_path, b"/a.txt", false, true, None, false).unwrap();
+        fs::remove_dir_all(&history_dir).unwrap();
+        add_path_to_history(&history_path, b"/b.txt", false, true, None, false).unwrap();
+        let entries = lines(&history_path);
+        assert_eq!(entries.len(), 1);
+        assert!(entries[0].ends_with("\t/b.txt"), "{:?}", entries);
+        // Compaction writes a temp file next to the history, so it needs the
+        // dir too. Converting to the NUL format makes it rewrite the file even
+        // under the caps.
+        let bytes = fs::read(&history_path).unwrap();
+        fs::remove_dir_all(&history_dir).unwrap();
+        let summary =
+            compact_history_file(&history_path, &bytes, 0.5, false, None, false, true).unwrap();
+        assert!(summary.converted);
+        let bytes = read_history_file(&history_path).unwrap();
+        let paths: Vec<&[u8]> = history_entries_from_most_recent(&bytes)
+            .map(|entry| entry.path)
+            .collect();
+        assert_eq!(paths, [&b"/b.txt"[..]]);
+    }
+
+    #[test]
+    fn test_history_dir_that_cant_be_recreated() {
+        let dir = TempDir::new().unwrap();
+        // A dangling symlink where the dir should be means opening the file
+        // fails with NotFound, but the dir can't be created either, and then
+        // the error names the dir.
+        let history_dir = dir.path().join("founder");
+        std::os::unix::fs::symlink(dir.path().join("gone/founder"), &history_dir).unwrap();
+        let history_path = history_dir.join("file_history");
+        let e =
+            add_path_to_history(&history_path, b"/a.txt", false, true, None, false).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            format!("failed to create history dir {}", history_dir.display())
+        );
+    }
+
+    #[test]
+    fn test_selection_log_query_whitespace() {
+        let dir = TempDir::new().unwrap();
+        let log_path = dir.path().join("selection_log");
+        add_to_selection_log(&log_path, b"/a.txt", b"one\ttwo\nthree", false, true).unwrap();
+        add_to_selection_log(&log_path, b"/b.txt", b"", false, true).unwrap();
+        // Tabs and newlines in the query would break up the line, so they
+        // become spaces. An empty query is an empty field.
+        let lines = lines(&log_path);
+        let fields: Vec<Vec<&str>> = lines
+            .iter()
+            .map(|line| line.split('\t').collect())
+            .collect();
+        assert_eq!(fields[0][1..], ["one two three", "/a.txt"]);
+        assert_eq!(fields[1][1..], ["", "/b.txt"]);
+    }
+
+    fn chosen(
+        vars: &[(&str, &str)],
+        data_local_dir: Option<&str>,
+    ) -> Option<(String, &'static str)> {
+        let var = |name: &str| {
+            vars.iter()
+                .find(|&&(key, _)| key == name)
+                .map(|&(_, value)| OsString::from(value))
+        };
+        choose_history_dir(var, data_local_dir.map(PathBuf::from))
+            .map(|(dir, source)| (dir.to_string_lossy().into_owned(), source))
+    }
+
+    #[test]
+    fn test_choose_history_dir() {
+        let all = [
+            ("FOUNDER_HISTORY_DIR", "/founder"),
+            ("XDG_DATA_HOME", "/xdg"),
+        ];
+        let local = Some("/home/me/.local/share");
+        assert_eq!(
+            chosen(&all, local),
+            Some(("/founder".into(), "$FOUNDER_HISTORY_DIR"))
+        );
+        assert_eq!(
+            chosen(&all[1..], local),
+            Some(("/xdg/founder".into(), "$XDG_DATA_HOME"))
+        );
+        assert_eq!(
+            chosen(&[], local),
+            Some((
+                "/home/me/.local/share/founder".into(),
+                "the default data dir"
+            ))
+        );
+        assert_eq!(chosen(&[], None), None);
+        // Empty variables are skipped, and so is a relative $XDG_DATA_HOME.
+        let skipped = [("FOUNDER_HISTORY_DIR", ""), ("XDG_DATA_HOME", "xdg")];
+        assert_eq!(
+            chosen(&skipped, local),
+            Some((
+                "/home/me/.local/share/founder".into(),
+                "the default data dir"
+            ))
+        );
+    }
+
+    #[test]
+    fn test_profile_file_names() {
+        assert_eq!(history_file_name("file_history", None), "file_history");
+        assert_eq!(
+            history_file_name("query_history", Some("work")),
+            "query_history.work"
+        );
+        check_profile_name("work").unwrap();
+        check_profile_name("work-2").unwrap();
+        for bad in &["", ".hidden", "a/b", "../up"] {
+            assert!(check_profile_name(bad).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn test_history_file_override() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("history.txt");
+        assert_eq!(history_file_override(path.as_os_str()).unwrap(), path);
+        let missing = dir.path().join("missing/history.txt");
+        let e = history_file_override(missing.as_os_str()).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            format!(
+                "history file directory doesn't exist: {}",
+                dir.path().join("missing").display()
+            )
+        );
+    }
+
+    #[test]
+    fn test_create_history_dir() {
+        let dir = TempDir::new().unwrap();
+        let history_dir = dir.path().join("a/b/founder");
+        create_history_dir(&history_dir).unwrap();
+        assert!(history_dir.is_dir());
+        // Creating it again is fine.
+        create_history_dir(&history_dir).unwrap();
+        fs::write(dir.path().join("file"), "").unwrap();
+        let under_a_file = dir.path().join("file/founder");
+        match create_history_dir(&under_a_file) {
+            Err(HistoryError::CreateHistoryDir { path, .. }) => assert_eq!(path, under_a_file),
+            other => panic!("{:?}", other),
+        }
+    }
+}
@@ -0,0 +1,68 @@
+// Shell code for `founder init <SHELL>`, which binds Ctrl-T to run founder and
+// insert the selection at the cursor, quoted for that shell. If founder exits
+// non-zero, because the user aborted, nothing matched, or there was an error,
+// the command line is left alone. Like fzf's own scripts, these take over
+// Ctrl-T, so load them after fzf's key bindings if you use both.
+
+pub const SHELLS: &[&str] = &["bash", "zsh", "fish"];
+
+const BASH: &str = r#"__founder_widget() {
+  local selected
+  selected="$(founder --no-newline)" || return
+  selected="$(printf '%q' "$selected")"
+  READLINE_LINE="${READLINE_LINE:0:$READLINE_POINT}${selected}${READLINE_LINE:$READLINE_POINT}"
+  READLINE_POINT=$(( READLINE_POINT + ${#selected} ))
+}
+bind -m emacs-standard -x '"\C-t": __founder_widget'
+bind -m vi-insert -x '"\C-t": __founder_widget'
+"#;
+
+const ZSH: &str = r#"__founder_widget() {
+  local selected
+  if selected="$(founder --no-newline)"; then
+    LBUFFER+="${(q)selected}"
+  fi
+  zle reset-prompt
+}
+zle -N __founder_widget
+bindkey -M emacs '^T' __founder_widget
+bindkey -M viins '^T' __founder_widget
+"#;
+
+const FISH: &str = r#"function __founder_widget
+    set -l selected (founder --no-newline)
+    and commandline --insert -- (string escape -- $selected)
+    commandline --function repaint
+end
+bind \ct __founder_widget
+bind -M insert \ct __founder_widget
+"#;
+
+pub fn script(shell: &str) -> &'static str {
+    match shell {
+        "bash" => BASH,
+        "zsh" => ZSH,
+        "fish" => FISH,
+        _ => unreachable!("clap checks the shell name"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Only bash gets run in the integration tests, since the others might not
+    // be installed.
+    #[test]
+    fn test_every_shell_runs_founder_and_binds_the_widget() {
+        for &shell in SHELLS {
+            let script = script(shell);
+            assert!(script.contains("(founder --no-newline)"), "{}", shell);
+            let binds = script
+                .lines()
+                .filter(|line| line.starts_with("bind") && line.contains("__founder_widget"))
+                .count();
+            assert_eq!(binds, 2, "{}", shell);
+        }
+    }
+}
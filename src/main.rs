@@ -1,266 +1,1016 @@
+mod bench;
+mod cache;
+mod history;
+mod init;
+mod session;
+
+use ahash::AHashSet;
 use anyhow::{anyhow, bail, Context, Result};
-use clap::{App, Arg, SubCommand};
+use clap::{App, AppSettings, Arg, ArgGroup, SubCommand};
 use duct::cmd;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use log::{debug, trace};
 use once_cell::sync::OnceCell;
-use std::collections::HashSet;
+use std::borrow::Cow;
 use std::env;
-use std::ffi::{OsStr, OsString};
+use std::ffi::{CStr, CString, OsStr, OsString};
 use std::fs;
 use std::io;
 use std::io::prelude::*;
-use std::path::{Path, PathBuf, MAIN_SEPARATOR};
+use std::mem;
+use std::path::{Component, Path, PathBuf};
 use std::process::ExitStatus;
+use std::ptr;
+use std::sync::mpsc;
+use std::time::Duration;
 // Unix-only for now.
-use std::os::unix::ffi::OsStrExt;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::process::ExitStatusExt;
+
+// Exit codes. Wrapper scripts can rely on these, for example to retry when
+// nothing matched but not when the user aborted. They're the same as fzf's.
+//   0: a selection was made
+//   1: the query didn't match anything
+//   2: an error, either in founder or reported by fzf
+//   130: the user aborted, with Escape or Ctrl-C
+const EXIT_NO_MATCH: i32 = 1;
+const EXIT_ERROR: i32 = 2;
+const EXIT_ABORTED: i32 = 130;
 
-const MAX_HISTORY_LINES: u64 = 1000;
+const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(60 * 60);
+const DEFAULT_COMPACT_RETAIN_FRACTION: f64 = 0.5;
+const FLUSH_INTERVAL_LINES: u64 = 1000;
+// History lines cost a stat each, so they go out in smaller batches.
+const HISTORY_FLUSH_INTERVAL_LINES: u64 = 100;
+const DEFAULT_FZF_HISTORY_SIZE: usize = 100;
+
+// The failures that callers might want to handle differently, from the history
+// functions and from running the finder. Everything else uses anyhow, and so
+// does main(). Other wraps those anyhow errors when they pass through one of
+// the functions that returns a FounderError.
+#[derive(Debug, thiserror::Error)]
+enum FounderError {
+    #[error(transparent)]
+    History(#[from] history::HistoryError),
+    #[error("failed to start {name} (is it installed?)")]
+    MissingBinary {
+        name: String,
+        #[source]
+        source: io::Error,
+    },
+    // fzf draws on /dev/tty, not on our stdin, so a redirected stdin is fine
+    // as long as there's a controlling terminal.
+    #[error(
+        "{name} needs a terminal, and there isn't one{hint}. Run founder from a \
+         terminal, or use --builtin to match without one."
+    )]
+    NoTerminal { name: String, hint: &'static str },
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
 
-fn history_dir() -> Result<&'static Path> {
-    static HISTORY_DIR: OnceCell<PathBuf> = OnceCell::new();
+// The history dir, and which step of history::default_history_dir's chain
+// picked it. It gets created the first time anything asks for it. None means
+// there's nowhere to put it. In that case founder still works, but nothing is
+// persisted.
+fn chosen_history_dir() -> Result<Option<&'static (PathBuf, &'static str)>, FounderError> {
+    static HISTORY_DIR: OnceCell<Option<(PathBuf, &'static str)>> = OnceCell::new();
     HISTORY_DIR
         .get_or_try_init(|| {
-            let user_data_dir = dirs::data_local_dir().ok_or_else(|| anyhow!("no data dir"))?;
-            let founder_dir = user_data_dir.join("founder");
-            fs::create_dir_all(&founder_dir).context("failed to create history dir")?;
-            Ok(founder_dir)
+            let chosen = history::default_history_dir();
+            match &chosen {
+                Some((dir, _)) => history::create_history_dir(dir)?,
+                None => eprintln!("founder: no data dir, history will not be saved"),
+            }
+            Ok::<_, FounderError>(chosen)
         })
-        .map(|p| p.as_ref())
+        .map(|chosen| chosen.as_ref())
+}
+
+fn history_dir() -> Result<Option<&'static Path>, FounderError> {
+    Ok(chosen_history_dir()?.map(|(dir, _)| dir.as_path()))
+}
+
+fn history_dir_source() -> Result<Option<&'static str>, FounderError> {
+    Ok(chosen_history_dir()?.map(|&(_, source)| source))
+}
+
+// Set from --history-file, before anything reads or writes the history. This
+// only affects the file history, not the query history.
+static FILE_HISTORY_PATH_OVERRIDE: OnceCell<PathBuf> = OnceCell::new();
+
+fn set_file_history_path_override(path: &OsStr) -> Result<()> {
+    FILE_HISTORY_PATH_OVERRIDE
+        .set(history::history_file_override(path)?)
+        .expect("history file override already set");
+    Ok(())
+}
+
+// Set from --profile, also before anything touches the history.
+static PROFILE: OnceCell<String> = OnceCell::new();
+
+fn set_profile(name: &str) -> Result<()> {
+    history::check_profile_name(name)?;
+    PROFILE.set(name.to_owned()).expect("profile already set");
+    Ok(())
+}
+
+fn history_dir_file(base: &str) -> Result<Option<PathBuf>, FounderError> {
+    let name = history::history_file_name(base, PROFILE.get().map(String::as_str));
+    Ok(history_dir()?.map(|dir| dir.join(name)))
+}
+
+// --history-file takes precedence over --profile.
+fn file_history_path() -> Result<Option<PathBuf>, FounderError> {
+    if let Some(path) = FILE_HISTORY_PATH_OVERRIDE.get() {
+        return Ok(Some(path.clone()));
+    }
+    history_dir_file("file_history")
 }
 
-fn file_history_path() -> Result<PathBuf> {
-    Ok(history_dir()?.join("file_history"))
+fn query_history_path() -> Result<Option<PathBuf>, FounderError> {
+    history_dir_file("query_history")
 }
 
-fn query_history_path() -> Result<PathBuf> {
-    Ok(history_dir()?.join("query_history"))
+// With --log-selections, each selection also goes here along with the query
+// that found it. Nothing reads this file, and it's never compacted.
+fn selection_log_path() -> Result<Option<PathBuf>, FounderError> {
+    history_dir_file("selection_log")
 }
 
-fn file_history_bytes() -> Result<&'static [u8]> {
+fn file_history_bytes() -> Result<&'static [u8], FounderError> {
     static FILE_HISTORY_BYTES: OnceCell<Vec<u8>> = OnceCell::new();
     FILE_HISTORY_BYTES
-        .get_or_try_init(|| match fs::read(file_history_path()?) {
-            Ok(bytes) => Ok(bytes),
-            Err(e) => {
-                if e.kind() == io::ErrorKind::NotFound {
-                    // If the file didn't exist, just make an empty Vec.
-                    Ok(Vec::new())
-                } else {
-                    Err(e).context("failed to read history")
-                }
-            }
+        .get_or_try_init(|| {
+            let history_path = match file_history_path()? {
+                Some(path) => path,
+                // Without a history dir, the history is always empty.
+                None => return Ok(Vec::new()),
+            };
+            Ok::<_, FounderError>(history::read_history_file(&history_path)?)
         })
         .map(|b| b.as_ref())
 }
 
-// These lines do not include the terminating newline.
-fn history_lines_from_most_recent() -> Result<impl Iterator<Item = &'static [u8]>> {
-    let bytes = file_history_bytes()?;
-    Ok(bstr::ByteSlice::rsplit_str(bytes, "\n").filter(|line| !line.is_empty()))
+// The history module takes the history path and settings as arguments. These
+// wrappers supply them from the defaults and the Config.
+fn compact_history(config: &Config) -> Result<Option<history::CompactSummary>, FounderError> {
+    match file_history_path()? {
+        Some(path) => Ok(Some(history::compact_history_file(
+            &path,
+            file_history_bytes()?,
+            config.compact_retain_fraction,
+            config.ignore_case_paths,
+            config.max_history_bytes,
+            config.keep_duplicates,
+            config.history_null,
+        )?)),
+        None => Ok(None),
+    }
 }
 
-fn home_dir() -> Result<&'static Path> {
-    static HOME_DIR: OnceCell<PathBuf> = OnceCell::new();
-    HOME_DIR
-        .get_or_try_init(|| match dirs::home_dir() {
-            Some(homedir) => Ok(homedir),
-            None => bail!("home directory not configured"),
-        })
-        .map(|p| p.as_ref())
+// The compact subcommand runs the same compaction as the background thread,
+// but in the foreground, and says what it did. Like the background one, it
+// leaves a history that's under the caps alone.
+fn compact_command(config: &Config) -> Result<()> {
+    let summary = compact_history(config)?;
+    if summary.as_ref().is_some_and(|summary| summary.converted) {
+        println!("converted history to the NUL-separated format");
+    }
+    match summary {
+        Some(summary) if summary.lines_after < summary.lines_before => println!(
+            "compacted history from {} to {} lines, removed {}",
+            summary.lines_before,
+            summary.lines_after,
+            summary.lines_before - summary.lines_after,
+        ),
+        Some(summary) if summary.converted => {}
+        Some(summary) => println!(
+            "history has {} lines, nothing to compact",
+            summary.lines_before
+        ),
+        None => println!("no history dir, nothing to compact"),
+    }
+    Ok(())
 }
 
-fn compact_history_file() -> Result<()> {
-    // Iterate over all the history lines, starting with the most recent, and
-    // collect the first unique occurrence of each one into a vector.
-    let mut total_lines: u64 = 0;
-    let mut lines_set = HashSet::new();
-    let mut ordered_unique_lines = Vec::new();
-    for line in history_lines_from_most_recent()? {
-        total_lines += 1;
-        if lines_set.insert(line) {
-            ordered_unique_lines.push(line);
-        }
+// The import subcommand reads paths from a file, or from stdin if there's no
+// file argument. If the input has a NUL byte anywhere, it's NUL-delimited,
+// and otherwise it's newline-delimited. Paths are recorded in the order they
+// appear, relative ones against the cwd, and repeats are dropped. Afterwards
+// we compact the history, since an import can easily go over the cap, unless
+// --no-compact says not to.
+fn import_history(config: &Config, input: Option<&OsStr>) -> Result<()> {
+    let history_path = match file_history_path()? {
+        Some(path) => path,
+        None => bail!("no history dir, nothing to import into"),
+    };
+    let bytes = match input {
+        Some(path) => fs::read(path).with_context(|| format!("failed to read {:?}", path))?,
+        None => stdin_candidates()?.to_vec(),
+    };
+    let separator = if bytes.contains(&b'\0') { b'\0' } else { b'\n' };
+    let mut seen = AHashSet::new();
+    let paths: Vec<&[u8]> = bytes
+        .split(|&byte| byte == separator)
+        .filter(|path| !history::is_bogus_path(path))
+        .filter(|path| seen.insert(history::dedup_key(config.ignore_case_paths, path)))
+        .collect();
+    let recorded = history::add_paths_to_history(
+        &history_path,
+        &paths,
+        config.resolve_symlinks,
+        !config.no_normalize,
+        None,
+        config.history_null,
+    )?;
+    eprintln!("founder: imported {} paths", recorded);
+    if !config.no_compact {
+        history::compact_history_file(
+            &history_path,
+            &history::read_history_file(&history_path)?,
+            config.compact_retain_fraction,
+            config.ignore_case_paths,
+            config.max_history_bytes,
+            config.keep_duplicates,
+            config.history_null,
+        )?;
+    }
+    Ok(())
+}
+
+fn add_to_history(config: &Config, path: &[u8]) -> Result<()> {
+    match file_history_path()? {
+        Some(history_path) => history::add_path_to_history(
+            &history_path,
+            path,
+            config.resolve_symlinks,
+            !config.no_normalize,
+            current_git_branch(config),
+            config.history_null,
+        ),
+        None => Ok(()),
+    }
+}
+
+// With --branch-aware, the git branch checked out in the cwd. New history
+// entries are tagged with it, and entries tagged with it rank first. Outside
+// of a git repo, on a detached HEAD, or without git installed, there's no
+// branch, and founder behaves as if the flag wasn't given.
+fn current_git_branch(config: &Config) -> Option<&'static str> {
+    static CURRENT_GIT_BRANCH: OnceCell<Option<String>> = OnceCell::new();
+    if !config.branch_aware {
+        return None;
     }
-    // If the history file does not need to be truncated, short-circuit.
-    if total_lines <= MAX_HISTORY_LINES {
+    CURRENT_GIT_BRANCH
+        .get_or_init(|| {
+            // Unlike rev-parse --abbrev-ref, symbolic-ref works on a branch
+            // with no commits yet, and fails on a detached HEAD.
+            let output = cmd!("git", "symbolic-ref", "--short", "-q", "HEAD")
+                .stdout_capture()
+                .stderr_null()
+                .unchecked()
+                .run()
+                .ok()?;
+            if !output.status.success() {
+                return None;
+            }
+            let branch = String::from_utf8(output.stdout).ok()?;
+            let branch = branch.trim_end();
+            // The history format can't hold whitespace here, and git doesn't
+            // allow it in branch names anyway.
+            if branch.is_empty() || branch.contains(char::is_whitespace) {
+                return None;
+            }
+            Some(branch.to_owned())
+        })
+        .as_deref()
+}
+
+// A selection from the finder goes into the history, and with
+// --log-selections into the selection log too, unless --no-record is given.
+fn record_selection(config: &Config, path: &[u8], query: &[u8]) -> Result<()> {
+    if config.no_record {
         return Ok(());
     }
-    // Retain only half the maximum number of lines. (Though pruning duplicates
-    // above might already have brought us below that.) This means that we'll
-    // go a long time between compactions, rather than compacting all the time
-    // when the history file is full of unique entries.
-    ordered_unique_lines.truncate((MAX_HISTORY_LINES / 2) as usize);
-    // Write the remaining lines to a temporary file. Once the lines are
-    // written, we'll swap it with the real history file. Note that this
-    // temporary file must be on the same filesystem as the real one, so a
-    // standard temp file in /tmp doesn't work here.
-    let temp_file_path = file_history_path()?.with_extension("tmp");
-    let temp_file = fs::OpenOptions::new()
-        .write(true)
-        .create_new(true) // error if the file already exists
-        .open(&temp_file_path)?;
-    let mut temp_file_writer = io::BufWriter::new(temp_file);
-    // Note that lines in the history file are oldest-to-newest, which is the
-    // opposite of what's in our vector here, so we reverse it.
-    for line in ordered_unique_lines.iter().rev() {
-        temp_file_writer.write_all(line)?;
-        temp_file_writer.write_all(b"\n")?;
-    }
-    temp_file_writer.flush()?;
-    drop(temp_file_writer);
-    // Swap the new history file into place.
-    fs::rename(&temp_file_path, file_history_path()?)?;
+    add_to_history(config, path)?;
+    if config.log_selections {
+        if let Some(log_path) = selection_log_path()? {
+            history::add_to_selection_log(
+                &log_path,
+                path,
+                query,
+                config.resolve_symlinks,
+                !config.no_normalize,
+            )?;
+        }
+    }
     Ok(())
 }
 
-fn add_path_to_history(path: &[u8]) -> Result<()> {
-    let path_osstr = OsStr::from_bytes(path);
-    // Note that we don't use std::fs::canonicalize here. That fails for files
-    // that don't exist. (A common example is "vim foo.txt". That file doesn't
-    // exist until you save it, but we want to add it to history immediately.)
-    // It's also better not to resolve symbolic links, but to allow different
-    // paths to the same file to exist separately in history.
-    let mut absolute_path = path_abs::PathAbs::new(path_osstr)?
-        .as_path()
-        .as_os_str()
-        .to_owned();
-    // The path does not have an extra newline at the end, so we add one.
-    absolute_path.push("\n");
-    let mut history_file = fs::OpenOptions::new()
-        .append(true)
-        .create(true)
-        .open(file_history_path()?)?;
-    history_file.write_all(absolute_path.as_bytes())?;
-    Ok(())
+// In --stdin mode, the candidates come from our own stdin rather than from fd.
+// We read it all up front, because switching modes runs the finder again, and
+// stdin can only be read once.
+fn stdin_candidates() -> Result<&'static [u8]> {
+    static STDIN_CANDIDATES: OnceCell<Vec<u8>> = OnceCell::new();
+    STDIN_CANDIDATES
+        .get_or_try_init(|| {
+            let mut bytes = Vec::new();
+            io::stdin()
+                .read_to_end(&mut bytes)
+                .context("failed to read stdin")?;
+            Ok(bytes)
+        })
+        .map(|b| b.as_ref())
+}
+
+// A short, human-readable age like "5m ago" or "3d ago".
+fn format_age(timestamp: u64, now: u64) -> String {
+    let seconds = now.saturating_sub(timestamp);
+    if seconds < 60 {
+        "just now".to_string()
+    } else if seconds < 60 * 60 {
+        format!("{}m ago", seconds / 60)
+    } else if seconds < 24 * 60 * 60 {
+        format!("{}h ago", seconds / (60 * 60))
+    } else {
+        format!("{}d ago", seconds / (24 * 60 * 60))
+    }
+}
+
+// Returns None if the home directory isn't configured. In that case we don't
+// do any ~/ substitution.
+fn home_dir() -> Option<&'static Path> {
+    static HOME_DIR: OnceCell<Option<PathBuf>> = OnceCell::new();
+    HOME_DIR.get_or_init(dirs::home_dir).as_deref()
+}
+
+// Converts a candidate path into the form that we show in fzf. Candidates are
+// either absolute paths from history or relative paths from fd, and this is
+// the one place where both get transformed, so that the display is uniform.
+// Paths under the cwd are shown relative to it. With --relative, absolute
+// paths that share a parent with the cwd (other than the root) are also shown
+// relative, using ../ components. Remaining paths under the home directory are
+// shown with ~/ substituted, and everything else is left absolute. Whatever we
+// produce here needs to round-trip through expand_selection.
+//
+// This runs for every candidate, so the caller looks up the home directory
+// once and passes it in, and each prefix check is a single strip_prefix
+// rather than a starts_with followed by a strip_prefix.
+fn display_path(path: &Path, cwd: &Path, home: Option<&Path>, relative: bool) -> Result<PathBuf> {
+    if let Ok(rest) = path.strip_prefix(cwd) {
+        // The cwd itself is ., rather than an empty line that fzf would show
+        // as blank and that wouldn't round-trip as a path.
+        if rest.as_os_str().is_empty() {
+            return Ok(PathBuf::from("."));
+        }
+        return Ok(escape_leading_tilde(rest));
+    }
+    if relative && path.is_absolute() {
+        if let Some(relative_path) = dot_dot_relative_path(path, cwd) {
+            return Ok(escape_leading_tilde(&relative_path));
+        }
+    }
+    if let Some(rest) = home.and_then(|home| path.strip_prefix(home).ok()) {
+        // If the path is underneath the home directory, substitute in a ~/.
+        // The home directory itself is just ~, because joining an empty path
+        // would add a trailing slash that doesn't round-trip.
+        if rest.as_os_str().is_empty() {
+            return Ok(PathBuf::from("~"));
+        }
+        return Ok(Path::new("~").join(rest));
+    }
+    Ok(escape_leading_tilde(path))
+}
+
+// If the first component of the path starts with a literal ~, prepend a
+// dot-slash. That prevents us from getting confused when we read leading ~ or
+// ~user back out from FZF.
+fn escape_leading_tilde(path: &Path) -> PathBuf {
+    if path.as_os_str().as_bytes().starts_with(b"~") {
+        Path::new(".").join(path)
+    } else {
+        path.to_owned()
+    }
+}
+
+// Returns the path relative to the cwd, going up with ../ as needed, or None
+// if the two only have the root in common.
+fn dot_dot_relative_path(path: &Path, cwd: &Path) -> Option<PathBuf> {
+    let mut path_components = path.components().peekable();
+    let mut cwd_components = cwd.components().peekable();
+    let mut common_components = 0;
+    while let (Some(a), Some(b)) = (path_components.peek(), cwd_components.peek()) {
+        if a != b {
+            break;
+        }
+        path_components.next();
+        cwd_components.next();
+        common_components += 1;
+    }
+    // The first common component is the root.
+    if common_components <= 1 {
+        return None;
+    }
+    let mut relative_path = PathBuf::new();
+    for _ in cwd_components {
+        relative_path.push("..");
+    }
+    relative_path.extend(path_components);
+    Some(relative_path)
 }
 
-// Substitute ~/ for the home directory.
+// With --show-age, every line gets a tab and an annotation (possibly empty)
+// after the path. fzf doesn't match against the annotation, and
+// strip_annotation removes it from the selection.
+//
+// With --ansi, the parent directories are dim and the file name is bold. fzf
+// strips the color codes from the selection itself, and strip_ansi takes care
+// of anything else.
 fn write_path_to_fzf(
-    path_bytes: &[u8],
+    config: &Config,
+    display_path: &Path,
+    annotation: Option<&str>,
     fzf_buf_writer: &mut io::BufWriter<os_pipe::PipeWriter>,
 ) -> Result<()> {
-    let path = Path::new(OsStr::from_bytes(path_bytes));
-    let mut separator_buf = [0; 4];
-    let separator = MAIN_SEPARATOR.encode_utf8(&mut separator_buf);
-    if path.starts_with(home_dir()?) {
-        // If the path is underneath the home directory, substitute in a ~/.
-        let rest = path.strip_prefix(home_dir()?).unwrap();
-        fzf_buf_writer.write_all(b"~")?;
-        fzf_buf_writer.write_all(separator.as_bytes())?;
-        fzf_buf_writer.write_all(rest.as_os_str().as_bytes())?;
-    } else if path.starts_with("~") {
-        // If the first entire component of the path is a literal ~, prepend a
-        // dot-slash. That prevents us from getting confused when we read
-        // leading ~ back out from FZF.
-        fzf_buf_writer.write_all(b".")?;
-        fzf_buf_writer.write_all(separator.as_bytes())?;
-        fzf_buf_writer.write_all(path_bytes)?;
+    let path_bytes = display_path.as_os_str().as_bytes();
+    if config.ansi {
+        let name_start = bstr::ByteSlice::rfind_byte(path_bytes, b'/').map_or(0, |slash| slash + 1);
+        if name_start > 0 {
+            fzf_buf_writer.write_all(b"\x1b[2m")?;
+            fzf_buf_writer.write_all(&path_bytes[..name_start])?;
+            fzf_buf_writer.write_all(b"\x1b[0m")?;
+        }
+        fzf_buf_writer.write_all(b"\x1b[1m")?;
+        fzf_buf_writer.write_all(&path_bytes[name_start..])?;
+        fzf_buf_writer.write_all(b"\x1b[0m")?;
     } else {
-        // Otherwise just write the path without any changes.
         fzf_buf_writer.write_all(path_bytes)?;
     }
-    fzf_buf_writer.write_all(b"\n")?;
+    if let Some(annotation) = annotation {
+        fzf_buf_writer.write_all(b"\t")?;
+        fzf_buf_writer.write_all(annotation.as_bytes())?;
+    }
+    fzf_buf_writer.write_all(&[record_separator(config)])?;
     Ok(())
 }
 
-// Expands ~/
-fn expand_selection(selection: &[u8]) -> Result<Vec<u8>> {
+// With --null, paths are NUL-terminated everywhere: in fd's output (or our
+// stdin), on the way into and out of fzf, and in what we print. That's the
+// only way to handle filenames with newlines in them. Those paths only get
+// recorded when the history file is NUL-separated too, with --history-null
+// (or a history that was converted already). Otherwise they're skipped.
+fn record_separator(config: &Config) -> u8 {
+    if config.null {
+        b'\0'
+    } else {
+        b'\n'
+    }
+}
+
+// Removes escape sequences like the ones write_path_to_fzf adds: ESC [, any
+// parameter and intermediate bytes, and a final byte.
+fn strip_ansi<'a>(config: &Config, line: &'a [u8]) -> Cow<'a, [u8]> {
+    if !config.ansi || !line.contains(&0x1b) {
+        return Cow::Borrowed(line);
+    }
+    let mut stripped = Vec::with_capacity(line.len());
+    let mut bytes = line.iter().copied().peekable();
+    while let Some(byte) = bytes.next() {
+        if byte == 0x1b && bytes.peek() == Some(&b'[') {
+            bytes.next();
+            for byte in bytes.by_ref() {
+                if (0x40..=0x7e).contains(&byte) {
+                    break;
+                }
+            }
+        } else {
+            stripped.push(byte);
+        }
+    }
+    Cow::Owned(stripped)
+}
+
+// Paths can contain tabs, but annotations can't, so we split at the last one.
+fn strip_annotation<'a>(config: &Config, line: &'a [u8]) -> &'a [u8] {
+    if config.show_age {
+        if let Some(tab) = bstr::ByteSlice::rfind_byte(line, b'\t') {
+            return &line[..tab];
+        }
+    }
+    line
+}
+
+// Looks up a user's home directory in the password database.
+fn user_home_dir(user: &OsStr) -> Option<PathBuf> {
+    let name = CString::new(user.as_bytes()).ok()?;
+    let mut buf: Vec<libc::c_char> = vec![0; 1024];
+    // SAFETY: passwd is plain old data, and getpwnam_r only writes to it and
+    // to buf, whose real length we pass in.
+    unsafe {
+        let mut passwd: libc::passwd = mem::zeroed();
+        let mut result = ptr::null_mut();
+        loop {
+            let ret = libc::getpwnam_r(
+                name.as_ptr(),
+                &mut passwd,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut result,
+            );
+            if ret == libc::ERANGE {
+                // The buffer was too small for this entry. Try a bigger one.
+                buf.resize(buf.len() * 2, 0);
+                continue;
+            }
+            break;
+        }
+        if result.is_null() || passwd.pw_dir.is_null() {
+            return None;
+        }
+        let dir = CStr::from_ptr(passwd.pw_dir);
+        Some(PathBuf::from(OsStr::from_bytes(dir.to_bytes())))
+    }
+}
+
+// Expands ~/ and ~user/. An unknown user is left unchanged. Note that
+// display_path escapes real filenames that start with ~, so those don't make
+// it here. Like display_path, this takes the home directory from the caller.
+fn expand_selection(selection: &[u8], home: Option<&Path>) -> Result<Vec<u8>> {
     let path = Path::new(OsStr::from_bytes(selection));
-    let mut expanded;
-    if path.starts_with("~") {
+    let first_component = match path.components().next() {
+        Some(Component::Normal(first)) if first.as_bytes().starts_with(b"~") => first,
+        _ => return Ok(selection.to_vec()),
+    };
+    let home = if first_component == "~" {
         // If the first entire component is ~, then we need to expand that to
         // the home directory.
-        let rest = path.strip_prefix("~").unwrap();
-        let mut separator_buf = [0; 4];
-        let separator = MAIN_SEPARATOR.encode_utf8(&mut separator_buf);
-        expanded = home_dir()?.as_os_str().as_bytes().to_vec();
-        expanded.extend_from_slice(separator.as_bytes());
-        expanded.extend_from_slice(rest.as_os_str().as_bytes());
+        home.map(Path::to_owned)
+    } else {
+        user_home_dir(OsStr::from_bytes(&first_component.as_bytes()[1..]))
+    };
+    let home = match home {
+        Some(home) => home,
+        None => return Ok(selection.to_vec()),
+    };
+    // Use join rather than pasting in a separator, so that a home directory
+    // of / doesn't give us //. As in display_path, a bare ~ is the home
+    // directory itself, with no trailing slash.
+    let rest = path.strip_prefix(first_component).unwrap();
+    let expanded = if rest.as_os_str().is_empty() {
+        home
+    } else {
+        home.join(rest)
+    };
+    Ok(expanded.into_os_string().into_vec())
+}
+
+// fd handles --exclude itself, but history entries need the same filtering,
+// so that excluded files don't sneak back in. fd uses the ignore crate with
+// gitignore-style globs rooted at the search directory, and so do we.
+fn exclude_matcher(config: &Config, cwd: &Path) -> Result<Gitignore> {
+    let mut builder = GitignoreBuilder::new(cwd);
+    for glob in &config.excludes {
+        builder
+            .add_line(None, glob)
+            .with_context(|| format!("invalid --exclude glob: {}", glob))?;
+    }
+    Ok(builder.build()?)
+}
+
+// Patterns from --ignore-file apply to history entries the same way, as if
+// each line had been given to --exclude. fd reads these files even with
+// --no-ignore, so they apply in every mode.
+fn ignore_file_matcher(config: &Config, cwd: &Path) -> Result<Gitignore> {
+    let mut builder = GitignoreBuilder::new(cwd);
+    for ignore_file in &config.ignore_files {
+        if let Some(e) = builder.add(ignore_file) {
+            return Err(e)
+                .with_context(|| format!("invalid --ignore-file {}", ignore_file.display()));
+        }
+    }
+    Ok(builder.build()?)
+}
+
+// Entries under the cwd are excluded if they or any of their parent dirs (up
+// to the cwd) match. Entries elsewhere are only matched by themselves, so that
+// for example "*.o" still applies but the directories above the cwd don't
+// count.
+// Patterns in a .founderignore file hide candidates from both history and fd,
+// on top of --exclude and fd's own ignore files. There can be one in the cwd,
+// which works like a .gitignore rooted there, and one in the history dir,
+// which applies everywhere. Patterns in the latter are matched against
+// absolute paths, and a leading ~/ is expanded, so ~/Downloads hides
+// everything under the Downloads folder. Within a file, later lines win as
+// usual, including ! lines, but nothing in a .founderignore can bring back a
+// path that --exclude or fd already filtered out. We read these files again
+// every time the finder runs, so edits take effect on the next search.
+fn founderignore_matchers(cwd: &Path) -> Result<Vec<Gitignore>> {
+    let mut matchers = Vec::new();
+    let mut sources = vec![(cwd.join(".founderignore"), cwd.to_owned(), false)];
+    if let Some(dir) = history_dir()? {
+        sources.push((dir.join(".founderignore"), PathBuf::from("/"), true));
+    }
+    for (file_path, root, expand_tilde) in sources {
+        let contents = match fs::read_to_string(&file_path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => {
+                return Err(e).with_context(|| format!("failed to read {}", file_path.display()))
+            }
+        };
+        let mut builder = GitignoreBuilder::new(&root);
+        for line in contents.lines() {
+            let line = match (line.strip_prefix("~/"), home_dir()) {
+                (Some(rest), Some(home)) if expand_tilde => {
+                    Cow::Owned(format!("{}/{}", home.display(), rest))
+                }
+                _ => Cow::Borrowed(line),
+            };
+            builder
+                .add_line(Some(file_path.clone()), &line)
+                .with_context(|| format!("invalid glob in {}: {}", file_path.display(), line))?;
+        }
+        matchers.push(builder.build()?);
+    }
+    Ok(matchers)
+}
+
+// --fd-glob and --fd-regex narrow what fd finds, before fzf's fuzzy matching
+// sees anything. With --filter-history, history entries have to match the
+// same pattern. Like fd, we match the file name only, and the pattern is case
+// insensitive unless it has an uppercase letter in it. Globs get converted to
+// regexes, which is also what fd does.
+fn fd_pattern_matcher(config: &Config) -> Result<Option<regex::bytes::Regex>> {
+    if !config.filter_history {
+        return Ok(None);
+    }
+    let (pattern, regex) = match (&config.fd_glob, &config.fd_regex) {
+        (Some(glob), _) => {
+            let glob = globset::Glob::new(glob)
+                .with_context(|| format!("invalid --fd-glob pattern: {}", glob))?;
+            (glob.glob().to_owned(), glob.regex().to_owned())
+        }
+        (None, Some(regex)) => (regex.clone(), regex.clone()),
+        (None, None) => return Ok(None),
+    };
+    let case_insensitive = !pattern.chars().any(char::is_uppercase);
+    let matcher = regex::bytes::RegexBuilder::new(&regex)
+        .case_insensitive(case_insensitive)
+        .build()
+        .with_context(|| format!("invalid pattern: {}", pattern))?;
+    Ok(Some(matcher))
+}
+
+fn is_excluded(matcher: &Gitignore, path: &Path, cwd: &Path) -> bool {
+    if path.starts_with(cwd) {
+        matcher.matched_path_or_any_parents(path, false).is_ignore()
     } else {
-        expanded = selection.to_vec();
+        matcher.matched(path, false).is_ignore()
     }
-    Ok(expanded)
+}
+
+// How many candidates the input thread wrote to fzf from each source, for
+// --verbose. The fd count includes lines from stdin in --stdin mode.
+#[derive(Clone, Copy, Default)]
+struct CandidateCounts {
+    history: u64,
+    fd: u64,
 }
 
 // Inner, because we want to catch any BrokenPipe errors that this returns.
-// This takes a ReaderHandle for fd from the caller, because the caller might
-// kill it from another thread.
+// This takes a reader for the candidates from the caller. That's either a
+// ReaderHandle for fd, which the caller might kill from another thread, or the
+// buffered stdin in --stdin mode. The counts are an out-parameter, so that
+// they survive a BrokenPipe.
 fn input_thread_inner(
-    fd_reader: &duct::ReaderHandle,
+    mut candidates_reader: impl BufRead,
     fzf_stdin_writer: os_pipe::PipeWriter,
+    config: &Config,
     mode: &Mode,
+    counts: &mut CandidateCounts,
 ) -> Result<()> {
-    // Note that &ReaderHandle implements Read.
-    let mut fd_buf_reader = io::BufReader::new(fd_reader);
     let mut fzf_buf_writer = io::BufWriter::new(fzf_stdin_writer);
+    let separator = record_separator(config);
 
     // Write all the history lines to fzf first, and collect them in a set so
     // that we can filter out duplicates from older history lines and from fd.
-    // When we're not in "everything mode", skip over history entries that
+    // Unless the history scope is global, skip over history entries that
     // aren't under the current working directory. Note that we do include
     // hidden files from history, regardless of whether we're asking fd to
-    // search for them.
+    // search for them. With --no-history, we skip the history entirely, and
+    // the set stays empty.
     let cwd = env::current_dir()?;
-    let mut seen_history = HashSet::<&[u8]>::new();
-    for line in history_lines_from_most_recent()? {
-        let mut relative_line = Path::new(OsStr::from_bytes(line));
-        if relative_line.starts_with(&cwd) {
-            relative_line = relative_line.strip_prefix(&cwd).unwrap();
-        } else if !mode.global_history {
+    let home = home_dir();
+    let history_excludes = exclude_matcher(config, &cwd)?;
+    let history_ignore_files = ignore_file_matcher(config, &cwd)?;
+    let founderignores = founderignore_matchers(&cwd)?;
+    let is_founderignored = |absolute: &Path| {
+        founderignores
+            .iter()
+            .any(|matcher| is_excluded(matcher, absolute, matcher.path()))
+    };
+    let fd_pattern = fd_pattern_matcher(config)?;
+    let matches_query_prefix = query_prefix_matcher(config);
+    let matches_fd_pattern = |path: &Path| match (&fd_pattern, path.file_name()) {
+        (Some(pattern), Some(name)) => pattern.is_match(name.as_bytes()),
+        (Some(_), None) => false,
+        (None, _) => true,
+    };
+    let mut seen_history = AHashSet::<Vec<u8>>::new();
+    let mut missing_history = AHashSet::<&[u8]>::new();
+    let now = history::unix_now();
+    let history_bytes: &[u8] = if config.no_history {
+        b""
+    } else if let Some(history_bytes) = session::replay_history() {
+        history_bytes
+    } else {
+        file_history_bytes()?
+    };
+    let selection_counts = history::selection_counts(history_bytes, config.ignore_case_paths);
+    // With --branch-aware, these are the paths selected at least once on the
+    // current branch.
+    let branch = current_git_branch(config);
+    let branch_paths: AHashSet<Cow<[u8]>> = match branch {
+        Some(branch) => history::history_entries_from_most_recent(history_bytes)
+            .filter(|entry| entry.branch == Some(branch.as_bytes()))
+            .map(|entry| history::dedup_key(config.ignore_case_paths, entry.path))
+            .collect(),
+        None => AHashSet::new(),
+    };
+    // Usually each history candidate goes to fzf as soon as we've checked it,
+    // with a flush every so often, so that fzf can paint before we've stat'ed
+    // the whole history. --tiebreak frecency, --sort-by-path, and the --order
+    // values other than history-first reorder the candidates, so in those
+    // cases we collect them first, and so does --branch-aware in a git repo.
+    // Either way, fd lines wait until all the history is in seen_history, so
+    // that the dedup below never lets a duplicate through. A path that comes
+    // from both sources is the history entry, with its annotation, wherever
+    // the history entries go.
+    let reorder_history = config.tiebreak.as_deref() == Some("frecency")
+        || config.sort_by_path
+        || config.order != Order::HistoryFirst
+        || branch.is_some();
+    let mut history_candidates = Vec::new();
+    let mut unflushed_lines: u64 = 0;
+    // With --path, fd searches those roots instead of the cwd, and unless the
+    // history scope is global, history entries have to be under one of them.
+    let search_roots: Vec<&Path> = if config.paths.is_empty() {
+        vec![&cwd]
+    } else {
+        config.paths.iter().map(PathBuf::as_path).collect()
+    };
+    for entry in history::history_entries_from_most_recent(history_bytes) {
+        let path = Path::new(OsStr::from_bytes(entry.path));
+        // A NUL-format history can hold a path with a newline in it, but fzf
+        // can only show that with --null.
+        if entry.path.contains(&separator) {
+            debug!(
+                "skipping history entry {:?}, it contains the separator",
+                path
+            );
+            continue;
+        }
+        let search_root = search_roots.iter().find(|&&root| path.starts_with(root));
+        if search_root.is_none() && !mode.global_history {
+            trace!("skipping history entry {:?}, not under a search root", path);
+            continue;
+        }
+        let filtered_by = if is_excluded(&history_excludes, path, &cwd) {
+            Some("--exclude")
+        } else if is_excluded(&history_ignore_files, path, &cwd) {
+            Some("--ignore-file")
+        } else if is_founderignored(path) {
+            Some(".founderignore")
+        } else if !matches_fd_pattern(path) {
+            Some("the fd pattern")
+        } else {
+            None
+        };
+        if let Some(filter) = filtered_by {
+            debug!("skipping history entry {:?}, filtered by {}", path, filter);
+            continue;
+        }
+        // --depth applies to history entries under a search root, the same way
+        // it applies to fd. Entries elsewhere (with global history) don't
+        // have a meaningful depth, so they're not filtered.
+        if let (Some(depth), Some(root)) = (config.depth, search_root) {
+            let relative = path.strip_prefix(root).unwrap();
+            if relative.components().count() > depth {
+                debug!("skipping history entry {:?}, deeper than --depth", path);
+                continue;
+            }
+        }
+        let display = display_path(path, &cwd, home, config.relative)?;
+        if !matches_query_prefix(&display) {
+            continue;
+        }
+        let key = history::dedup_key(config.ignore_case_paths, display.as_os_str().as_bytes());
+        // With --keep-duplicates, every occurrence of a path in the history
+        // gets its own line, each with its own age. fd lines are still
+        // deduped against seen_history below.
+        if seen_history.contains(&*key) && !config.keep_duplicates {
+            continue;
+        }
+        // Deleted files are left out entirely, so anything that still exists
+        // ranks above them. Paths that come up again and again in the history
+        // would otherwise get stat'ed every time, so remember the missing ones.
+        // (The ones that exist go into seen_history below.)
+        if missing_history.contains(entry.path) {
             continue;
         }
-        let relative_line_bytes = relative_line.as_os_str().as_bytes();
-        if seen_history.contains(relative_line_bytes) {
+        if !path.exists() {
+            debug!("skipping history entry {:?}, it doesn't exist", path);
+            missing_history.insert(entry.path);
             continue;
         }
-        if !relative_line.exists() {
+        let selection_count = if config.keep_duplicates {
+            entry.count
+        } else {
+            selection_counts[&history::dedup_key(config.ignore_case_paths, entry.path)]
+        };
+        // With --show-age, the annotation also shows how many times a path
+        // has been selected, if it's more than once. With --keep-duplicates,
+        // that's only this line's count, which is more than one for lines that
+        // were merged before.
+        let annotation = if config.show_age {
+            let mut annotation = entry
+                .timestamp
+                .map(|t| format_age(t, now))
+                .unwrap_or_default();
+            if selection_count > 1 {
+                if !annotation.is_empty() {
+                    annotation.push_str(", ");
+                }
+                annotation.push_str(&format!("{}x", selection_count));
+            }
+            Some(annotation)
+        } else {
+            None
+        };
+        seen_history.insert(key.into_owned());
+        counts.history += 1;
+        if reorder_history {
+            let on_branch =
+                branch_paths.contains(&history::dedup_key(config.ignore_case_paths, entry.path));
+            history_candidates.push((display, annotation, selection_count, on_branch));
             continue;
         }
-        write_path_to_fzf(relative_line_bytes, &mut fzf_buf_writer)?;
-        seen_history.insert(relative_line_bytes);
+        write_path_to_fzf(config, &display, annotation.as_deref(), &mut fzf_buf_writer)?;
+        unflushed_lines += 1;
+        if unflushed_lines >= HISTORY_FLUSH_INTERVAL_LINES {
+            fzf_buf_writer.flush()?;
+            unflushed_lines = 0;
+        }
+    }
+    // With --tiebreak frecency, more frequently selected paths go first, and
+    // the sort is stable, so recency breaks ties between equal counts. Then
+    // fzf's --tiebreak=index makes earlier candidates win ties between equal
+    // match scores.
+    if config.tiebreak.as_deref() == Some("frecency") {
+        history_candidates.sort_by_key(|&(_, _, count, _)| std::cmp::Reverse(count));
+    }
+    // With --branch-aware, paths selected on the current branch go before the
+    // rest. This sort is stable too, so within each group the order above
+    // still holds.
+    if branch.is_some() {
+        history_candidates.sort_by_key(|&(_, _, _, on_branch)| !on_branch);
+    }
+    // With --sort-by-path, nothing is written until we've read everything from
+    // fd, and then all the candidates go to fzf in sorted order. That means
+    // holding the whole listing in memory, which can be a lot on a big tree,
+    // and fzf doesn't show anything until fd is done.
+    let mut sorted_candidates = Vec::new();
+    let mut history_candidates = history_candidates
+        .into_iter()
+        .map(|(display, annotation, _, _)| (display, annotation));
+    if config.sort_by_path {
+        sorted_candidates.extend(history_candidates.by_ref());
+    } else if config.order == Order::HistoryFirst {
+        for (display, annotation) in history_candidates.by_ref() {
+            write_path_to_fzf(config, &display, annotation.as_deref(), &mut fzf_buf_writer)?;
+        }
     }
     fzf_buf_writer.flush()?;
+    unflushed_lines = 0;
 
-    // Now write lines from fd to fzf, filtering out duplicates as noted above.
+    // Now write lines from fd (or from stdin) to fzf, filtering out duplicates
+    // as noted above.
+    // Flush periodically, so that fzf's match counter keeps updating while fd
+    // is still walking a large tree, rather than jumping when the BufWriter
+    // happens to fill.
+    //
+    // --max-results caps the number of lines we take from fd (or stdin), after
+    // dedup. History entries don't count toward the cap, since there are at
+    // most history::MAX_HISTORY_LINES of them, and it's fd on a huge tree
+    // that can use up lots of memory.
     let mut line = Vec::new();
+    let mut written_lines: usize = 0;
     loop {
+        if Some(written_lines) == config.max_results {
+            debug!("stopped reading candidates at --max-results");
+            break;
+        }
         line.clear();
         // Read a line from fd. This will implicitly wait on the fd child
         // process if the read encounters EOF, though if fd was killed then the
         // killing thread may have awaited it already.
-        let n = fd_buf_reader.read_until(b'\n', &mut line)?;
+        let n = candidates_reader.read_until(separator, &mut line)?;
         if n == 0 {
-            // The output from fd is finished. This thread is done.
-            fzf_buf_writer.flush()?;
-            return Ok(());
+            // The output from fd is finished.
+            break;
         }
         // Check the line we just read against the lines from the history file,
-        // and suppress any duplicates.
-        assert_eq!(line[line.len() - 1], b'\n');
-        let stripped_line = &line[..line.len() - 1];
-        if seen_history.contains(stripped_line) {
+        // and suppress any duplicates. The last line of stdin might not have a
+        // terminating newline (or NUL), and blank lines are skipped.
+        let stripped_line = line.strip_suffix(&[separator]).unwrap_or(&line);
+        if stripped_line.is_empty() {
+            continue;
+        }
+        let path = Path::new(OsStr::from_bytes(stripped_line));
+        if is_founderignored(&cwd.join(path)) {
+            trace!("skipping candidate {:?}, filtered by .founderignore", path);
+            continue;
+        }
+        let display = display_path(path, &cwd, home, config.relative)?;
+        if !matches_query_prefix(&display) {
+            continue;
+        }
+        if seen_history.contains(&*history::dedup_key(
+            config.ignore_case_paths,
+            display.as_os_str().as_bytes(),
+        )) {
+            trace!("skipping candidate {:?}, already in the history", path);
             continue;
         }
-        write_path_to_fzf(stripped_line, &mut fzf_buf_writer)?;
+        let no_age = if config.show_age { Some("") } else { None };
+        written_lines += 1;
+        counts.fd += 1;
+        if config.sort_by_path {
+            sorted_candidates.push((display, no_age.map(String::from)));
+            continue;
+        }
+        // With --order interleaved, each fd line goes after a history line,
+        // for as long as there are history lines left.
+        if config.order == Order::Interleaved {
+            if let Some((history_display, annotation)) = history_candidates.next() {
+                write_path_to_fzf(
+                    config,
+                    &history_display,
+                    annotation.as_deref(),
+                    &mut fzf_buf_writer,
+                )?;
+            }
+        }
+        write_path_to_fzf(config, &display, no_age, &mut fzf_buf_writer)?;
+        unflushed_lines += 1;
+        if unflushed_lines >= FLUSH_INTERVAL_LINES {
+            fzf_buf_writer.flush()?;
+            unflushed_lines = 0;
+        }
+    }
+    // With --order fd-first, this is all of the history, and with interleaved
+    // it's whatever outlasted fd. It's nothing otherwise.
+    for (display, annotation) in history_candidates {
+        write_path_to_fzf(config, &display, annotation.as_deref(), &mut fzf_buf_writer)?;
+    }
+    // Dedup has already happened above, so this is just the sort.
+    sorted_candidates
+        .sort_by(|(a, _), (b, _)| a.as_os_str().as_bytes().cmp(b.as_os_str().as_bytes()));
+    for (display, annotation) in &sorted_candidates {
+        write_path_to_fzf(config, display, annotation.as_deref(), &mut fzf_buf_writer)?;
     }
+    fzf_buf_writer.flush()?;
+    Ok(())
 }
 
-// Catches BrokenPipe errors. This takes a ReaderHandle for fd from the caller,
-// because the caller might kill it from another thread.
+// Catches BrokenPipe errors. This takes a reader for the candidates from the
+// caller, because the caller might kill fd from another thread.
 fn input_thread(
-    fd_reader: &duct::ReaderHandle,
+    candidates_reader: impl BufRead,
     fzf_stdin_writer: os_pipe::PipeWriter,
+    config: &Config,
     mode: &Mode,
-) -> Result<()> {
+) -> Result<CandidateCounts> {
     // Ignore BrokenPipe errors from input_thread_inner(). We do that here, at
     // a relatively high level, because we do want these errors to
     // short-circuit the entire input thread.
-    match input_thread_inner(fd_reader, fzf_stdin_writer, mode) {
-        Ok(()) => Ok(()),
+    let mut counts = CandidateCounts::default();
+    match input_thread_inner(
+        candidates_reader,
+        fzf_stdin_writer,
+        config,
+        mode,
+        &mut counts,
+    ) {
+        Ok(()) => Ok(counts),
         Err(e) => {
             let maybe_io: Option<&io::Error> = e.root_cause().downcast_ref();
             if let Some(io_error) = maybe_io {
                 if io_error.kind() == io::ErrorKind::BrokenPipe {
-                    return Ok(());
+                    return Ok(counts);
                 }
             }
             Err(e)
@@ -268,180 +1018,2435 @@ fn input_thread(
     }
 }
 
-fn fzf_command(config: &Config, mode: &Mode, query: &OsStr) -> Result<duct::Expression> {
-    let exe = if config.tmux { "fzf-tmux" } else { "fzf" };
-    Ok(cmd!(
-        exe,
-        "--prompt",
-        format!("{}> ", mode.mode_name),
-        "--expect=ctrl-t",
-        "--print-query",
-        "--query",
-        query,
-        "--history",
-        query_history_path()?,
-        "--history-size=100"
-    ))
+// With --prompt, any {mode} token in the user's prompt is replaced with the
+// current mode name. The --query-prefix goes at the end, so that it looks like
+// the start of the query.
+fn prompt(config: &Config, mode: &Mode) -> String {
+    let mut prompt = match &config.prompt {
+        Some(prompt) => prompt.replace("{mode}", mode.mode_name),
+        None => format!("{}> ", mode.mode_name),
+    };
+    if let Some(prefix) = &config.query_prefix {
+        prompt.push_str(prefix);
+        prompt.push(' ');
+    }
+    prompt
 }
 
-fn run_finder_once(config: &Config, mode: &Mode, query: &OsStr) -> Result<(ExitStatus, Vec<u8>)> {
-    // Open the stdin pipe for FZF. The input thread will receive the write
-    // end.
-    let (fzf_stdin_reader, fzf_stdin_writer) = os_pipe::pipe()?;
-
-    // Start the fd child process with a stdout reader. Each line of output
-    // from fd will become input to fzf, if it's not a duplicate of what was
-    // already shown from history. In "everything mode", tell fd to include
-    // hidden files. The fd command is unchecked() because we will kill it if
-    // it's still running when the user makes a selection. That's also why we
-    // start it here, instead of just letting the input thread do it.
-    let mut fd_args = vec!["--type=f", "--strip-cwd-prefix"];
-    if mode.fd_hidden_files {
-        fd_args.push("--hidden");
+// --query-prefix is part of the query that the user can't edit or delete. fzf
+// has no such thing, so the prefix is shown in the prompt, and we apply it
+// here, before fzf sees any candidates. Each word of the prefix has to fuzzy
+// match, like the words of an fzf query, using the same matcher as --builtin,
+// or with --exact it has to appear as is. fzf ranks the survivors by what the
+// user types.
+fn query_prefix_matcher(config: &Config) -> impl Fn(&Path) -> bool + '_ {
+    let matcher = fuzzy_matcher::skim::SkimMatcherV2::default();
+    let words: Vec<&str> = config
+        .query_prefix
+        .as_deref()
+        .map(|prefix| prefix.split_whitespace().collect())
+        .unwrap_or_default();
+    move |display: &Path| {
+        if words.is_empty() {
+            return true;
+        }
+        let mut text = display.as_os_str().as_bytes();
+        if config.match_basename {
+            if let Some(slash) = bstr::ByteSlice::rfind_byte(text, b'/') {
+                text = &text[slash + 1..];
+            }
+        }
+        let text = String::from_utf8_lossy(text);
+        words.iter().all(|word| {
+            if config.exact {
+                exact_match(&text, word)
+            } else {
+                fuzzy_matcher::FuzzyMatcher::fuzzy_match(&matcher, &text, word).is_some()
+            }
+        })
     }
-    let fd_reader = cmd("fd", &fd_args)
-        .unchecked()
-        .reader()
-        .context("failed to start fd (is it installed?)")?;
-
-    // Start the input thread, then await output from fzf.
-    crossbeam_utils::thread::scope(|scope| {
-        // Start the background thread that reads the fd pipe and continues
-        // writing to the fzf pipe.
-        let input_thread = scope.spawn(|_| input_thread(&fd_reader, fzf_stdin_writer, mode));
-
-        // Run FZF and capture its output. This is unchecked() because it
-        // returns an error code if the user's filter doesn't match anything,
-        // and we'll want to exit with the same code in that case without
-        // printing a failure message.
-        let fzf_output = fzf_command(config, mode, query)?
-            .stdin_file(fzf_stdin_reader)
-            .stdout_capture()
-            .unchecked()
-            .run()
-            .context("failed to start fzf or fzf-tmux (are they installed?)")?;
-
-        // Kill fd if it's still running, and return an error if the fd thread
-        // encountered one. This implicitly waits on the fd child process. Note
-        // that because of this potential kill signal, fd is unchecked(), and
-        // exiting with a non-zero status is not considered an error. Errors
-        // here are either a rare OS failure (out of memory?) or a bug.
-        fd_reader.kill()?;
-        input_thread.join().unwrap()?;
-
-        Ok((fzf_output.status, fzf_output.stdout))
-    })
-    .expect("panic in threading scope")
 }
 
-struct Mode {
-    global_history: bool,
-    fd_hidden_files: bool,
-    mode_name: &'static str,
+// A substring match for --exact, with fzf's smart case: a word with no
+// uppercase letters matches either case.
+fn exact_match(text: &str, word: &str) -> bool {
+    if word.chars().any(char::is_uppercase) {
+        text.contains(word)
+    } else {
+        text.to_lowercase().contains(word)
+    }
 }
 
-fn run_finder_loop(config: &Config) -> Result<()> {
-    const NUM_MODES: usize = 2;
-    let mut mode_number: usize = 0;
-    let mut previous_query = OsString::new();
-    loop {
-        let mode = match mode_number {
-            0 => Mode {
-                global_history: false,
-                fd_hidden_files: false,
-                mode_name: "local",
-            },
-            1 => Mode {
-                global_history: true,
-                fd_hidden_files: true,
-                mode_name: "everything",
-            },
-            _ => unreachable!("invalid mode"),
-        };
-
-        let (fzf_status, fzf_output) = run_finder_once(config, &mode, &previous_query)?;
-
-        // The first line of output is the query string, the second is the
-        // selection key (enter or ctrl-t), and the third line is the selection
-        // (possibly empty with an accompanying error status). Note that these
-        // split components will not include trailing newlines.
-        let mut parts = bstr::ByteSlice::split_str(&fzf_output[..], "\n");
-        let used_query = OsStr::from_bytes(parts.next().expect("no query line"));
-        let key = parts.next().expect("no key line");
-        let selection = expand_selection(parts.next().expect("no selection line"))?;
-
-        // Check the key before the status. The user may have a query that
-        // matches nothing, in which case Ctrl-T will lead to a non-zero
-        // status, which we ignore.
-        match key {
-            b"" => {
-                // This is the newline case, which means the user has made a
-                // selection. Record that selection to history, write it to
-                // stdout, and exit.
-
-                // If Fzf exited with an error code, we exit with that same
-                // code. For example, we get an error code if the user's filter
-                // didn't match anything.
-                if !fzf_status.success() {
-                    std::process::exit(fzf_status.code().unwrap_or(1));
-                }
+// The interactive finder that we drive. Skim takes the same flags as fzf for
+// everything founder needs (--prompt, --expect, --print-query, --query,
+// --history, --history-size), and its --print-query/--expect output has the
+// same three-line layout, so fzf_command and the parsing in run_finder_loop
+// work for both. Skim has no fzf-tmux equivalent, so --tmux only applies to
+// fzf.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+enum Finder {
+    #[default]
+    Fzf,
+    Skim,
+}
 
-                // Absolutify the selection and add that to the history file.
-                add_path_to_history(&selection)?;
+impl Finder {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "fzf" => Some(Finder::Fzf),
+            "skim" | "sk" => Some(Finder::Skim),
+            _ => None,
+        }
+    }
 
-                // Write the selection to stdout. Add a newline to be
-                // compatible with FZF, unless --no-newline is specified.
-                io::stdout().write_all(&selection)?;
-                if !config.no_newline {
-                    io::stdout().write_all(b"\n")?;
-                }
-                io::stdout().flush()?;
-                return Ok(());
-            }
-            b"ctrl-t" => {
-                // The user pressed Ctrl-T. We change modes, preserving the
-                // query string, and repeat this loop.
-                mode_number = (mode_number + 1) % NUM_MODES;
-                previous_query.clear();
-                previous_query.push(used_query);
-                continue;
-            }
-            _ => panic!(
-                "unexpected selector key: {:?}",
-                String::from_utf8_lossy(key)
-            ),
+    fn exe(self, tmux: bool) -> &'static str {
+        match (self, tmux) {
+            (Finder::Fzf, false) => "fzf",
+            (Finder::Fzf, true) => "fzf-tmux",
+            (Finder::Skim, _) => "sk",
         }
     }
 }
 
-fn clap_parse_argv() -> clap::ArgMatches<'static> {
-    App::new("founder")
-        .arg(Arg::with_name("no-newline").long("no-newline"))
-        .arg(Arg::with_name("tmux").long("tmux"))
-        .subcommand(
-            SubCommand::with_name("add").arg(Arg::with_name("path").index(1).required(true)),
-        )
-        .get_matches()
+// How input_thread_inner sequences history and fd (or stdin) candidates, from
+// --order. The default, history-first, is the original behavior.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+enum Order {
+    #[default]
+    HistoryFirst,
+    FdFirst,
+    Interleaved,
 }
 
-struct Config {
-    no_newline: bool,
-    tmux: bool,
+impl Order {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "history-first" => Some(Order::HistoryFirst),
+            "fd-first" => Some(Order::FdFirst),
+            "interleaved" => Some(Order::Interleaved),
+            _ => None,
+        }
+    }
 }
 
-fn main() -> Result<()> {
-    let compactor_thread = std::thread::spawn(compact_history_file);
-    let matches = clap_parse_argv();
-    let command_result = if let Some(add_matches) = matches.subcommand_matches("add") {
-        let path = add_matches.value_of_os("path").unwrap().as_bytes();
-        add_path_to_history(path)
-    } else {
-        let config = Config {
-            no_newline: matches.is_present("no-newline"),
-            tmux: matches.is_present("tmux"),
+// --finder wins over FOUNDER_FINDER, and the default is fzf.
+fn finder(matches: &clap::ArgMatches) -> Result<Finder> {
+    if let Some(name) = matches.value_of("finder") {
+        return Ok(Finder::from_name(name).unwrap()); // already validated
+    }
+    match env::var("FOUNDER_FINDER") {
+        Ok(name) => Finder::from_name(&name)
+            .ok_or_else(|| anyhow!("FOUNDER_FINDER must be fzf or skim, found {:?}", name)),
+        Err(_) => Ok(Finder::Fzf),
+    }
+}
+
+// fzf refuses to start if it can't write its --history file. A read-only home
+// can leave the query history unwritable even when --history-file moved the
+// file history somewhere else, so we check first, and without write access we
+// warn (once) and go without query history.
+fn writable_query_history_path() -> Result<Option<&'static Path>, FounderError> {
+    static QUERY_HISTORY_PATH: OnceCell<Option<PathBuf>> = OnceCell::new();
+    let path = QUERY_HISTORY_PATH.get_or_try_init(|| -> Result<_, FounderError> {
+        let path = match query_history_path()? {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        // If the file doesn't exist yet, fzf needs to be able to create it.
+        let target = if path.exists() {
+            path.as_path()
+        } else {
+            path.parent().unwrap_or_else(|| Path::new("/"))
+        };
+        let target_cstr =
+            CString::new(target.as_os_str().as_bytes()).map_err(anyhow::Error::from)?;
+        // SAFETY: access only reads the NUL-terminated path we give it.
+        if unsafe { libc::access(target_cstr.as_ptr(), libc::W_OK) } != 0 {
+            eprintln!(
+                "founder: {} isn't writable, query history is disabled",
+                target.display()
+            );
+            return Ok(None);
+        }
+        Ok(Some(path))
+    })?;
+    Ok(path.as_deref())
+}
+
+// FOUNDER_FZF_BIN replaces whichever of fzf or fzf-tmux we'd run, so that a
+// scripted stub can stand in for the real thing, in tests or anywhere without
+// a terminal. It doesn't apply to skim.
+fn finder_exe(config: &Config) -> OsString {
+    match (&config.fzf_bin, config.finder) {
+        (Some(fzf_bin), Finder::Fzf) => fzf_bin.clone(),
+        _ => config.finder.exe(config.tmux).into(),
+    }
+}
+
+fn fzf_command(
+    config: &Config,
+    mode: &Mode,
+    query: &OsStr,
+) -> Result<duct::Expression, FounderError> {
+    let exe = finder_exe(config);
+    let mut fzf_args: Vec<OsString> = Vec::new();
+    // fzf-tmux geometry options like -p or -d go first, and only to fzf-tmux.
+    if config.tmux {
+        fzf_args.extend(config.tmux_opts.iter().map(OsString::from));
+    }
+    fzf_args.extend(vec![
+        "--prompt".into(),
+        prompt(config, mode).into(),
+        expect_arg().into(),
+        "--print-query".into(),
+        "--query".into(),
+        query.into(),
+    ]);
+    // Color the prompt differently in each mode, so that switching modes is
+    // easy to see. fzf combines multiple --color options, so this keeps any
+    // other colors from FZF_DEFAULT_OPTS.
+    if config.color {
+        fzf_args.push(format!("--color=prompt:{}", mode.prompt_color).into());
+    }
+    // fzf parses FZF_DEFAULT_OPTS before its command line arguments, so if
+    // both set the layout, --layout wins.
+    if let Some(layout) = &config.layout {
+        fzf_args.push(format!("--layout={}", layout).into());
+    }
+    if config.ansi {
+        fzf_args.push("--ansi".into());
+    }
+    for bind in &config.bind {
+        fzf_args.push(format!("--bind={}", bind).into());
+    }
+    if config.cycle {
+        fzf_args.push("--cycle".into());
+    }
+    // --exact only changes how fzf matches the query. Dedup between history
+    // and fd compares whole paths either way.
+    if config.exact {
+        fzf_args.push("--exact".into());
+    }
+    if config.no_mouse {
+        fzf_args.push("--no-mouse".into());
+    }
+    // fzf-tmux picks its own pane size (see also --tmux-opts), so --height
+    // only goes to plain fzf.
+    if let (Some(height), false) = (&config.height, config.tmux) {
+        fzf_args.push(format!("--height={}", height).into());
+    }
+    if config.null {
+        fzf_args.push("--read0".into());
+        fzf_args.push("--print0".into());
+    }
+    // With --no-sort, fzf keeps the input order for every query, not just the
+    // empty one. Since history is written first, most recent first, that means
+    // recent history always beats fd results that happen to match better.
+    // --sort-by-path sorts the input itself, and fzf needs to keep that order.
+    if config.no_sort || config.sort_by_path {
+        fzf_args.push("--no-sort".into());
+    }
+    match config.tiebreak.as_deref() {
+        Some("frecency") => fzf_args.push("--tiebreak=index".into()),
+        Some(tiebreak) => fzf_args.push(format!("--tiebreak={}", tiebreak).into()),
+        None => {}
+    }
+    // Don't match against the age annotation, which is the last tab-delimited
+    // field. With --match-basename, split on slashes too, and match only the
+    // last path component, which is the last field before the annotation.
+    // The line itself is unchanged, so fzf still shows the whole path, and
+    // prints it as the selection.
+    match (config.show_age, config.match_basename) {
+        (true, false) => {
+            fzf_args.push("--delimiter=\t".into());
+            fzf_args.push("--nth=..-2".into());
+        }
+        (true, true) => {
+            fzf_args.push("--delimiter=[/\t]".into());
+            fzf_args.push("--nth=-2".into());
+        }
+        (false, true) => {
+            fzf_args.push("--delimiter=/".into());
+            fzf_args.push("--nth=-1".into());
+        }
+        (false, false) => {}
+    }
+    // Without a history dir, there's no query history either. fzf trims the
+    // query history file to --history-size itself whenever it saves a query,
+    // so we don't need to compact it.
+    if let Some(query_history_path) = writable_query_history_path()? {
+        fzf_args.push("--history".into());
+        fzf_args.push(query_history_path.into());
+        fzf_args.push(format!("--history-size={}", config.fzf_history_size).into());
+    }
+    Ok(cmd(exe, fzf_args))
+}
+
+// Start the fd child process with a stdout reader. Each line of output from fd
+// will become input to fzf, if it's not a duplicate of what was already shown
+// from history. With hidden files toggled on, tell fd to include them. The fd
+// command is unchecked() because we will kill it if it's still running when
+// the user makes a selection. That's also why the caller starts it, instead of
+// just letting the input thread do it. In --stdin mode we don't run fd at all.
+fn start_fd(config: &Config, mode: &Mode) -> Result<Option<duct::ReaderHandle>, FounderError> {
+    if config.stdin {
+        return Ok(None);
+    }
+    debug!("running fd with {:?}", fd_args(config, mode));
+    let fd_reader = cmd("fd", fd_args(config, mode))
+        .unchecked()
+        .reader()
+        .map_err(|source| FounderError::MissingBinary {
+            name: "fd".into(),
+            source,
+        })?;
+    Ok(Some(fd_reader))
+}
+
+// With --cache, this looks for a saved fd listing before start_fd() runs. The
+// cache lives next to the history, so without a history dir there's no cache,
+// and with --stdin there's no fd to cache.
+fn open_fd_cache(config: &Config, mode: &Mode) -> Result<Option<cache::FdCache>> {
+    if !config.cache || config.stdin {
+        return Ok(None);
+    }
+    let cache_dir = match history_dir()? {
+        Some(dir) => dir.join("fd_cache"),
+        None => return Ok(None),
+    };
+    let cwd = env::current_dir()?;
+    let roots = if config.paths.is_empty() {
+        vec![cwd.clone()]
+    } else {
+        config.paths.clone()
+    };
+    let fd_cache = cache::FdCache::open(
+        &cache_dir,
+        &cwd,
+        &fd_args(config, mode),
+        &roots,
+        config.cache_ttl,
+    )?;
+    Ok(Some(fd_cache))
+}
+
+// A listing only goes in the cache if fd finished on its own. fd is
+// unchecked(), so a kill from --fd-timeout, or from us when fzf exits first,
+// shows up here as a failed status rather than as an error. This is called
+// after kill(), which waits for fd.
+fn fd_finished(reader: &duct::ReaderHandle) -> io::Result<bool> {
+    Ok(reader
+        .try_wait()?
+        .is_some_and(|output| output.status.success()))
+}
+
+// Failing to write the cache is worth a warning, but not worth failing the
+// search over.
+fn store_fd_cache(fd_cache: &cache::FdCache, fd_output: &[u8]) {
+    debug!(
+        "storing {} bytes of fd output in the cache",
+        fd_output.len()
+    );
+    if let Err(e) = fd_cache.store(fd_output) {
+        eprintln!("founder: {:#}", e);
+    }
+}
+
+// Passes reads through from fd and keeps a copy for the cache. eof records
+// whether the input thread read everything, which it doesn't with
+// --max-results.
+struct CopyingReader<R> {
+    inner: R,
+    copy: Vec<u8>,
+    eof: bool,
+}
+
+impl<R: Read> Read for CopyingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if n == 0 && !buf.is_empty() {
+            self.eof = true;
+        }
+        self.copy.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+}
+
+fn fd_args(config: &Config, mode: &Mode) -> Vec<OsString> {
+    let mut fd_args: Vec<OsString> = Vec::new();
+    if config.fd_types.is_empty() {
+        fd_args.push("--type=f".into());
+    }
+    for fd_type in &config.fd_types {
+        // "all" is ours, not fd's. It lists files and directories together,
+        // and selecting a directory works just like selecting a file.
+        if fd_type == "all" {
+            fd_args.push("--type=f".into());
+            fd_args.push("--type=d".into());
+        } else {
+            fd_args.push(format!("--type={}", fd_type).into());
+        }
+    }
+    // fd rejects --strip-cwd-prefix along with search paths, and the --path
+    // roots are absolute anyway, so there's no ./ prefix to strip.
+    if config.paths.is_empty() {
+        fd_args.push("--strip-cwd-prefix".into());
+    }
+    if mode.fd_hidden_files {
+        fd_args.push("--hidden".into());
+    }
+    if mode.fd_no_ignore {
+        fd_args.push("--no-ignore".into());
+    }
+    if config.null {
+        fd_args.push("--print0".into());
+    }
+    // fd detects symlink cycles and doesn't loop forever, but a link to a
+    // parent directory or to somewhere big like / can still make the listing
+    // much larger than expected.
+    if config.follow {
+        fd_args.push("--follow".into());
+    }
+    if let Some(depth) = config.depth {
+        fd_args.push(format!("--max-depth={}", depth).into());
+    }
+    // fd uses a thread per core by default. --fd-threads=1 keeps it from
+    // competing with everything else on a busy machine.
+    if let Some(threads) = config.fd_threads {
+        fd_args.push(format!("--threads={}", threads).into());
+    }
+    for glob in &config.excludes {
+        fd_args.push("--exclude".into());
+        fd_args.push(glob.into());
+    }
+    for ignore_file in &config.ignore_files {
+        fd_args.push("--ignore-file".into());
+        fd_args.push(ignore_file.into());
+    }
+    // The pattern is fd's first positional argument, after -- in case it
+    // starts with a dash. fd patterns are regexes unless --glob is given. With
+    // --path, the search roots come after the pattern, so then we need one
+    // even without --fd-glob or --fd-regex, and the empty regex matches
+    // everything. The roots are absolute, so fd prints absolute paths.
+    if config.fd_glob.is_some() {
+        fd_args.push("--glob".into());
+    }
+    let pattern = config.fd_glob.as_ref().or(config.fd_regex.as_ref());
+    if pattern.is_some() || !config.paths.is_empty() {
+        fd_args.push("--".into());
+        fd_args.push(pattern.map_or("", String::as_str).into());
+    }
+    fd_args.extend(config.paths.iter().map(OsString::from));
+    fd_args
+}
+
+// With --builtin, we match in-process instead of running fzf, for systems that
+// don't have it. This isn't interactive. The query comes from --query, and the
+// best match is the selection, with ties going to the earlier candidate, so
+// history still comes first. An empty query selects the first candidate, like
+// pressing Enter right away in fzf. With --exact, every word of the query has
+// to appear as is, and the fuzzy score only ranks those candidates. The output
+// and the exit status imitate fzf's, so that run_finder_loop handles both the
+// same way.
+fn run_builtin_matcher(
+    config: &Config,
+    candidates: impl Read,
+    query: &OsStr,
+) -> io::Result<(ExitStatus, Vec<u8>)> {
+    let separator = record_separator(config);
+    let matcher = fuzzy_matcher::skim::SkimMatcherV2::default();
+    let pattern = query.to_string_lossy();
+    let words: Vec<&str> = pattern.split_whitespace().collect();
+    let mut best: Option<(i64, Vec<u8>)> = None;
+    for candidate in io::BufReader::new(candidates).split(separator) {
+        let candidate = candidate?;
+        let mut choice = strip_annotation(config, &candidate);
+        if config.match_basename {
+            if let Some(slash) = bstr::ByteSlice::rfind_byte(choice, b'/') {
+                choice = &choice[slash + 1..];
+            }
+        }
+        let choice = String::from_utf8_lossy(choice);
+        let score = fuzzy_matcher::FuzzyMatcher::fuzzy_match(&matcher, &choice, &pattern);
+        let score = if config.exact {
+            if !words.iter().all(|word| exact_match(&choice, word)) {
+                continue;
+            }
+            score.unwrap_or(0)
+        } else {
+            match score {
+                Some(score) => score,
+                None => continue,
+            }
+        };
+        if best
+            .as_ref()
+            .is_none_or(|(best_score, _)| score > *best_score)
+        {
+            best = Some((score, candidate));
+        }
+    }
+    let mut output = query.as_bytes().to_vec();
+    output.push(separator);
+    // The key is always Enter, which fzf prints as an empty line.
+    output.push(separator);
+    let code = match best {
+        Some((_, selection)) => {
+            output.extend_from_slice(&selection);
+            output.push(separator);
+            0
+        }
+        None => EXIT_NO_MATCH,
+    };
+    Ok((ExitStatus::from_raw(code << 8), output))
+}
+
+// The caller starts fd with start_fd(), or start_first_fd() for the first
+// pass, so that fd is already walking the tree while we set up the pipe and
+// the input thread and spawn fzf. Cold caches make that walk the slowest part
+// of startup.
+fn run_finder_once(
+    config: &Config,
+    mode: &Mode,
+    fd_reader: Option<duct::ReaderHandle>,
+    fd_cache: Option<&cache::FdCache>,
+    replay: Option<&session::ReplayPass>,
+    query: &OsStr,
+) -> Result<(ExitStatus, Vec<u8>, CandidateCounts, Vec<u8>), FounderError> {
+    // Open the stdin pipe for FZF. The input thread will receive the write
+    // end.
+    check_terminal(config)?;
+    let (fzf_stdin_reader, fzf_stdin_writer) = os_pipe::pipe()?;
+    // A replayed pass's candidates work just like a cached listing, except
+    // that there's no fd running to refresh them.
+    let replayed = replay.map(|pass| pass.candidates(record_separator(config)));
+    let cached = replayed
+        .as_deref()
+        .or_else(|| fd_cache.and_then(cache::FdCache::cached));
+    // With --record-session, the input thread also returns a copy of the
+    // candidates it read, other than history. Otherwise that's empty.
+    let recording = config.record_session.is_some();
+    let copy_if_recording = |candidates: &[u8]| {
+        if recording {
+            candidates.to_vec()
+        } else {
+            Vec::new()
+        }
+    };
+
+    // Start the input thread, then await output from fzf.
+    crossbeam_utils::thread::scope(|scope| {
+        // Start the background thread that reads the fd pipe (or the stdin
+        // buffer, or the cached listing) and continues writing to the fzf
+        // pipe. Note that &ReaderHandle implements Read.
+        let input_thread = scope.spawn(|_| match (&fd_reader, cached) {
+            (_, Some(cached)) => input_thread(cached, fzf_stdin_writer, config, mode)
+                .map(|counts| (counts, copy_if_recording(cached))),
+            (Some(reader), None) => {
+                let mut copying_reader = CopyingReader {
+                    inner: reader,
+                    copy: Vec::new(),
+                    eof: false,
+                };
+                let result = input_thread(
+                    io::BufReader::new(&mut copying_reader),
+                    fzf_stdin_writer,
+                    config,
+                    mode,
+                );
+                // With --max-results, the input thread can stop reading before
+                // fd is done. Kill fd now rather than leaving it blocked on a
+                // full pipe until fzf exits. Otherwise fd has already exited
+                // or fzf has, and this is harmless.
+                reader.kill()?;
+                if let Some(fd_cache) = fd_cache {
+                    if copying_reader.eof && fd_finished(reader)? {
+                        store_fd_cache(fd_cache, &copying_reader.copy);
+                    }
+                }
+                result.map(|counts| (counts, copy_if_recording(&copying_reader.copy)))
+            }
+            (None, None) if config.stdin => {
+                let candidates = stdin_candidates()?;
+                input_thread(candidates, fzf_stdin_writer, config, mode)
+                    .map(|counts| (counts, copy_if_recording(candidates)))
+            }
+            // fd was deferred by --min-query-len, so there's only history.
+            (None, None) => input_thread(io::empty(), fzf_stdin_writer, config, mode)
+                .map(|counts| (counts, Vec::new())),
+        });
+
+        // When fzf is showing a cached listing, fd is still running, to
+        // refresh the cache. This thread collects its output. If fd finishes
+        // before fzf exits, the new listing replaces the old one, and
+        // otherwise the kill below cuts it short and we keep the old one.
+        let refresh_thread = match (&fd_reader, cached) {
+            (Some(reader), Some(_)) => Some(scope.spawn(move |_| {
+                let mut fd_output = Vec::new();
+                (&*reader).read_to_end(&mut fd_output)?;
+                Ok::<Vec<u8>, io::Error>(fd_output)
+            })),
+            _ => None,
+        };
+
+        // With --fd-timeout, a timer thread kills fd if it's still running
+        // when the timeout expires. fzf keeps whatever candidates arrived
+        // before that, and the input thread sees EOF from fd just like it
+        // would if fd finished on its own. Dropping the sender below, after
+        // fzf exits, wakes up the timer early.
+        let (fzf_done_sender, fzf_done_receiver) = mpsc::channel::<()>();
+        let timer_thread = match (&fd_reader, config.fd_timeout) {
+            (Some(reader), Some(timeout)) if cached.is_none() => Some(scope.spawn(move |_| {
+                if let Err(mpsc::RecvTimeoutError::Timeout) =
+                    fzf_done_receiver.recv_timeout(timeout)
+                {
+                    eprintln!("founder: fd timed out, showing partial results");
+                    debug!("killing fd after --fd-timeout");
+                    reader.kill()?;
+                }
+                Ok::<(), io::Error>(())
+            })),
+            _ => None,
+        };
+
+        // Run FZF and capture its output. This is unchecked() because it
+        // returns an error code if the user's filter doesn't match anything,
+        // and we'll want to exit with the same code in that case without
+        // printing a failure message.
+        let (fzf_status, fzf_stdout) = if let Some(pass) = replay {
+            pass.run_stub(fzf_stdin_reader, record_separator(config))?
+        } else if config.builtin {
+            run_builtin_matcher(config, fzf_stdin_reader, query)?
+        } else {
+            let fzf_output = fzf_command(config, mode, query)?
+                .stdin_file(fzf_stdin_reader)
+                .stdout_capture()
+                .unchecked()
+                .run()
+                .map_err(|source| FounderError::MissingBinary {
+                    name: finder_exe(config).to_string_lossy().into_owned(),
+                    source,
+                })?;
+            (fzf_output.status, fzf_output.stdout)
+        };
+        debug!("{:?} exited with {}", finder_exe(config), fzf_status);
+
+        // Kill fd if it's still running, and return an error if the fd thread
+        // encountered one. When fzf exits before fd is done, the input thread
+        // is either blocked writing to fzf or blocked reading from fd. The
+        // expression above owned our copy of the read end of the fzf pipe and
+        // dropped it when run() returned, so a blocked write fails with
+        // BrokenPipe. The kill unblocks a read from fd. Either way, the join
+        // below can't hang. This implicitly waits on the fd child
+        // process. Note that because of this potential kill signal, fd is
+        // unchecked(), and exiting with a non-zero status is not considered an
+        // error. Errors here are either a rare OS failure (out of memory?) or
+        // a bug.
+        drop(fzf_done_sender);
+        if let Some(timer_thread) = timer_thread {
+            timer_thread.join().unwrap()?;
+        }
+        if let Some(reader) = &fd_reader {
+            reader.kill()?;
+        }
+        if let (Some(refresh_thread), Some(reader), Some(fd_cache)) =
+            (refresh_thread, &fd_reader, fd_cache)
+        {
+            let fd_output = refresh_thread.join().unwrap()?;
+            if fd_finished(reader)? {
+                store_fd_cache(fd_cache, &fd_output);
+            } else {
+                debug!("fd didn't finish refreshing the cache, keeping the old one");
+            }
+        }
+        let (counts, candidates) = input_thread.join().unwrap()?;
+
+        Ok((fzf_status, fzf_stdout, counts, candidates))
+    })
+    .expect("panic in threading scope")
+}
+
+// The directory containing a selection, for Ctrl-O and --dirname. A relative
+// path with no parent, like "a.txt", is in the cwd, so that's ".". The root
+// directory is its own parent.
+fn parent_dir(path: &[u8]) -> &Path {
+    let path = Path::new(OsStr::from_bytes(path));
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        Some(_) => Path::new("."),
+        None if path.has_root() => Path::new("/"),
+        None => Path::new("."),
+    }
+}
+
+// Opens the directory containing the selection in the OS file manager.
+fn open_parent_dir(path: &[u8]) -> Result<()> {
+    let parent = parent_dir(path);
+    let opener = if cfg!(target_os = "macos") {
+        "open"
+    } else {
+        "xdg-open"
+    };
+    // Shell widgets capture our stdout as the selection, so anything the
+    // opener prints goes to stderr instead, like with --on-select.
+    cmd!(opener, parent)
+        .stdout_to_stderr()
+        .run()
+        .with_context(|| format!("failed to run {}", opener))?;
+    Ok(())
+}
+
+// With --confirm, asks on the terminal before a selection is recorded or
+// printed. The answer comes from /dev/tty, since stdin might be the candidate
+// list, and the prompt goes to stderr, since stdout is for the selection.
+// Anything but a yes, including an empty line, is a no.
+fn confirm_selection(selection: &[u8]) -> Result<bool> {
+    let tty = fs::File::open("/dev/tty").context("--confirm needs a terminal")?;
+    eprint!(
+        "founder: select {}? [y/N] ",
+        String::from_utf8_lossy(selection)
+    );
+    io::stderr().flush()?;
+    let mut answer = String::new();
+    io::BufReader::new(tty).read_line(&mut answer)?;
+    Ok(is_yes(&answer))
+}
+
+fn is_yes(answer: &str) -> bool {
+    matches!(answer.trim(), "y" | "Y" | "yes")
+}
+
+// Runs the --on-select command on the selection. Like the editor variables,
+// the command is split on whitespace, and then any {} in its words is replaced
+// with the path. Its stdout goes to our stderr, so that it doesn't get mixed
+// up with the selection we print. If it fails, we exit with its status.
+fn run_on_select(command: &OsStr, path: &[u8]) -> Result<()> {
+    let mut words = bstr::ByteSlice::fields(command.as_bytes())
+        .map(|word| OsString::from_vec(bstr::ByteSlice::replace(word, "{}", path)));
+    let exe = words
+        .next()
+        .ok_or_else(|| anyhow!("--on-select command is empty"))?;
+    let args: Vec<OsString> = words.collect();
+    let status = cmd(&exe, args)
+        .stdout_to_stderr()
+        .unchecked()
+        .run()
+        .with_context(|| format!("failed to start --on-select command {:?}", command))?
+        .status;
+    if !status.success() {
+        std::process::exit(status.code().unwrap_or(EXIT_ERROR));
+    }
+    Ok(())
+}
+
+// Fail early with a clear error when fzf would have no terminal to draw on,
+// like in a cron job or a CI script, instead of leaving fzf to complain. With
+// --tmux, fzf runs in a tmux pane, which has its own terminal. A
+// $FOUNDER_FZF_BIN might be a scripted stand-in that doesn't need one, so we
+// leave that to it, and a --replay-session doesn't run fzf at all. If our
+// stdin is a regular file, the caller probably meant for it to be the
+// candidates.
+fn check_terminal(config: &Config) -> Result<(), FounderError> {
+    if config.builtin || config.tmux || config.fzf_bin.is_some() || session::replaying() {
+        return Ok(());
+    }
+    if fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/tty")
+        .is_ok()
+    {
+        return Ok(());
+    }
+    let stdin_is_file = !config.stdin
+        && fs::metadata("/dev/stdin").is_ok_and(|metadata| metadata.file_type().is_file());
+    Err(FounderError::NoTerminal {
+        name: finder_exe(config).to_string_lossy().into_owned(),
+        hint: if stdin_is_file {
+            " (to read candidates from stdin, add --stdin)"
+        } else {
+            ""
+        },
+    })
+}
+
+// Runs $VISUAL or $EDITOR on the selection and waits for it. If the editor
+// fails, we exit with its status. The editor variable can include arguments,
+// like "code -w".
+fn open_in_editor(path: &[u8]) -> Result<()> {
+    let editor = env::var_os("VISUAL")
+        .filter(|v| !v.is_empty())
+        .or_else(|| env::var_os("EDITOR").filter(|v| !v.is_empty()))
+        .ok_or_else(|| anyhow!("--edit needs $VISUAL or $EDITOR to be set"))?;
+    let mut editor_words = bstr::ByteSlice::fields(editor.as_bytes()).map(OsStr::from_bytes);
+    let editor_exe = editor_words
+        .next()
+        .ok_or_else(|| anyhow!("--edit needs $VISUAL or $EDITOR to be set"))?;
+    let mut editor_args: Vec<&OsStr> = editor_words.collect();
+    editor_args.push(OsStr::from_bytes(path));
+    let status = cmd(editor_exe, editor_args)
+        .unchecked()
+        .run()
+        .with_context(|| format!("failed to start editor {:?}", editor))?
+        .status;
+    if !status.success() {
+        std::process::exit(status.code().unwrap_or(EXIT_ERROR));
+    }
+    Ok(())
+}
+
+fn find_on_path(exe: &str) -> Option<PathBuf> {
+    let path_var = env::var_os("PATH")?;
+    env::split_paths(&path_var)
+        .map(|dir| dir.join(exe))
+        .find(|candidate| candidate.is_file())
+}
+
+// Where the tool named exe is, for --version and doctor. $FOUNDER_FZF_BIN
+// stands in for fzf here too, so that both describe what would actually run.
+// Like a command name, it's looked up on $PATH unless it has a slash in it.
+fn locate_tool(config: &Config, exe: &str) -> Option<PathBuf> {
+    match &config.fzf_bin {
+        Some(fzf_bin) if exe == "fzf" && config.finder == Finder::Fzf => {
+            let fzf_bin = Path::new(fzf_bin);
+            if fzf_bin.as_os_str().as_bytes().contains(&b'/') {
+                Some(fzf_bin.to_owned()).filter(|path| path.is_file())
+            } else {
+                find_on_path(&fzf_bin.to_string_lossy())
+            }
+        }
+        _ => find_on_path(exe),
+    }
+}
+
+fn tool_version(exe: &Path) -> String {
+    cmd!(exe, "--version")
+        .stdin_null()
+        .stdout_capture()
+        .stderr_null()
+        .unchecked()
+        .run()
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        .unwrap_or_else(|_| "failed to run".to_string())
+}
+
+// For bug reports, --version also shows the versions of fd and the finder.
+// Unlike doctor, a missing tool isn't an error here. Like write_output, this
+// ignores a BrokenPipe, for `founder --version | head -1`.
+fn print_versions(config: &Config) -> io::Result<()> {
+    let result = (|| {
+        let mut stdout = io::stdout().lock();
+        writeln!(stdout, "founder {}", env!("CARGO_PKG_VERSION"))?;
+        for exe in &["fd", config.finder.exe(false)] {
+            match locate_tool(config, exe) {
+                Some(path) => writeln!(stdout, "{}: {}", exe, tool_version(&path))?,
+                None => writeln!(stdout, "{}: not found", exe)?,
+            }
+        }
+        stdout.flush()
+    })();
+    match result {
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => {
+            debug!("stdout was closed before the versions were written");
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+// Checks for everything founder needs to run, and prints what it finds. Any
+// missing requirement makes this return an error, after all the checks have
+// been printed.
+fn doctor(config: &Config) -> Result<()> {
+    let mut all_ok = true;
+    let mut tools = vec![
+        ("fd", !config.stdin),
+        (config.finder.exe(false), !config.builtin),
+    ];
+    if config.finder == Finder::Fzf {
+        tools.push(("fzf-tmux", config.tmux));
+    }
+    for &(exe, required) in &tools {
+        let found = match locate_tool(config, exe) {
+            Some(path) => path,
+            None => {
+                let status = if required { "MISSING" } else { "not found" };
+                println!("{}: {}", exe, status);
+                all_ok &= !required;
+                continue;
+            }
         };
-        run_finder_loop(&config)
+        // fzf-tmux doesn't have a --version of its own.
+        if exe == "fzf-tmux" {
+            println!("{}: {}", exe, found.display());
+            continue;
+        }
+        println!("{}: {} ({})", exe, found.display(), tool_version(&found));
+    }
+    match history_dir()? {
+        Some(dir) => {
+            // Check that we can actually create files there, since both
+            // history writes and compaction need that.
+            let probe_path = dir.join(format!(".doctor.{}", std::process::id()));
+            let writable = fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&probe_path)
+                .is_ok();
+            let _ = fs::remove_file(&probe_path);
+            let status = if writable { "writable" } else { "NOT WRITABLE" };
+            println!("history dir: {} ({})", dir.display(), status);
+            all_ok &= writable;
+        }
+        None => println!("history dir: none, history will not be saved"),
+    }
+    if let Some(path) = file_history_path()? {
+        println!("file history: {}", path.display());
+    }
+    if let Some(path) = query_history_path()? {
+        println!("query history: {}", path.display());
+    }
+    if !all_ok {
+        bail!("some checks failed");
+    }
+    Ok(())
+}
+
+fn exit_code_for_fzf_status(status: ExitStatus) -> i32 {
+    match status.code() {
+        Some(1) => EXIT_NO_MATCH,
+        // fzf exits with 130 on Escape or Ctrl-C. If it was killed by a signal
+        // instead, there's no code at all, and we treat that as an abort too.
+        Some(130) | None => EXIT_ABORTED,
+        Some(_) => EXIT_ERROR,
+    }
+}
+
+// The keys we pass to fzf's --expect, other than Enter, and what they do. fzf
+// prints the key that ended the search, and run_finder_loop() looks it up
+// here. To add a key, add it to this list and handle its action there.
+//
+// Hidden files are on Alt-H rather than Ctrl-H, because many terminals send
+// Ctrl-H for Backspace, and fzf would report every Backspace as the key.
+#[derive(Clone, Copy)]
+enum KeyAction {
+    ToggleHistoryScope,
+    ToggleHidden,
+    OpenParentDir,
+}
+
+const EXPECT_KEYS: &[(&str, KeyAction)] = &[
+    ("ctrl-t", KeyAction::ToggleHistoryScope),
+    ("alt-h", KeyAction::ToggleHidden),
+    ("ctrl-o", KeyAction::OpenParentDir),
+];
+
+fn expect_arg() -> String {
+    let keys: Vec<&str> = EXPECT_KEYS.iter().map(|&(key, _)| key).collect();
+    format!("--expect={}", keys.join(","))
+}
+
+// --bind values go to fzf as is, except that a binding for one of our expect
+// keys would stop fzf from reporting that key, which run_finder_loop depends
+// on. Split on commas to find the bound keys. That can misread a comma inside
+// an action, but only in the direction of dropping a --bind we didn't need to.
+fn fzf_binds(matches: &clap::ArgMatches) -> Vec<String> {
+    let binds = match matches.values_of("bind") {
+        Some(binds) => binds,
+        None => return Vec::new(),
+    };
+    binds
+        .filter(|bind| {
+            let conflict = bind.split(',').find_map(|binding| {
+                let key = binding.split(':').next().unwrap();
+                EXPECT_KEYS
+                    .iter()
+                    .find(|&&(expect_key, _)| expect_key == key)
+            });
+            if let Some((key, _)) = conflict {
+                eprintln!("founder: ignoring --bind {:?}, founder uses {}", bind, key);
+            }
+            conflict.is_none()
+        })
+        .map(String::from)
+        .collect()
+}
+
+// With --print-mode, the name of the mode that the selection was made in goes
+// to stderr on a line by itself, so that a caller can tell whether the user
+// switched modes with Ctrl-T or Alt-H. --json includes the mode already.
+fn print_mode(config: &Config, mode: &Mode) {
+    if config.print_mode {
+        eprintln!("{}", mode.mode_name);
+    }
+}
+
+// The mode is two independent toggles. Ctrl-T switches the history scope
+// between entries under the cwd (or the --path roots) and the whole history,
+// and --no-cwd-filter starts it out global. Alt-H switches fd's --hidden.
+struct Mode {
+    global_history: bool,
+    fd_hidden_files: bool,
+    // Independent of the toggles, this comes from --no-ignore.
+    fd_no_ignore: bool,
+    mode_name: &'static str,
+    // An fzf color name, for the prompt with --color.
+    prompt_color: &'static str,
+}
+
+// With --max-query-len, a longer query (from --query, or carried over by a
+// mode switch) gets cut down to that many bytes before it's handed to fzf.
+// The cut backs up to a UTF-8 character boundary, so it doesn't leave half a
+// character at the end.
+fn cap_query_len(config: &Config, query: &mut OsString) {
+    let max_len = match config.max_query_len {
+        Some(max_len) if query.len() > max_len => max_len,
+        _ => return,
     };
-    let compactor_result = compactor_thread.join().expect("compactor panic");
-    command_result.and(compactor_result)
+    let mut bytes = mem::take(query).into_vec();
+    let mut len = max_len;
+    while len > 0 && (bytes[len] & 0b1100_0000) == 0b1000_0000 {
+        len -= 1;
+    }
+    bytes.truncate(len);
+    eprintln!("founder: query truncated to {} bytes", len);
+    *query = OsString::from_vec(bytes);
+}
+
+// With --min-query-len, a starting query shorter than that many characters
+// means no fd, just history. fzf filters as the user types without telling us,
+// so we can't start fd later in the same run. Instead, Ctrl-T after a run like
+// that reruns the same mode with fd, and from then on fd always runs. Alt-H
+// only matters to fd, so it brings fd in and toggles hidden files at once.
+fn defer_fd(config: &Config, query: &OsStr) -> bool {
+    match config.min_query_len {
+        Some(min_len) => !config.stdin && query.to_string_lossy().chars().count() < min_len,
+        None => false,
+    }
+}
+
+// The names go in the prompt, in --print-mode, and in --json. "local" and
+// "everything" are the two modes from before the toggles were separate.
+fn finder_mode(config: &Config, global_history: bool, fd_hidden_files: bool) -> Mode {
+    let (mode_name, prompt_color) = match (global_history, fd_hidden_files) {
+        (false, false) => ("local", "blue"),
+        (false, true) => ("hidden", "cyan"),
+        (true, false) => ("global", "yellow"),
+        (true, true) => ("everything", "magenta"),
+    };
+    Mode {
+        global_history,
+        fd_hidden_files,
+        fd_no_ignore: config.no_ignore,
+        mode_name,
+        prompt_color,
+    }
+}
+
+// Writes the selection, or the query with --print-query-only, to stdout, with
+// a newline (or NUL) unless --no-newline is specified. If the reader of our
+// stdout has already exited, like `founder | true`, that's a BrokenPipe, and
+// like the input thread we ignore it. The selection is already recorded by
+// then, so we still exit with success.
+fn write_output(config: &Config, output: &[u8]) -> io::Result<()> {
+    let result = (|| {
+        let mut stdout = io::stdout().lock();
+        stdout.write_all(output)?;
+        if !config.no_newline {
+            stdout.write_all(&[record_separator(config)])?;
+        }
+        stdout.flush()
+    })();
+    match result {
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => {
+            debug!("stdout was closed before the output was written");
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+// The first pass of the finder doesn't need to wait for anything we do between
+// parsing the arguments and spawning fzf, so run() starts its fd right after
+// the Config is built, and fd walks the tree while we read the history, open
+// the --cache listing, and start the compaction thread. That only works when
+// the first pass would run fd anyway. If it turns out not to, dropping the
+// ReaderHandle kills fd.
+fn start_first_fd(config: &Config) -> Result<Option<duct::ReaderHandle>, FounderError> {
+    let query = config.query.clone().unwrap_or_default();
+    if config.dry_run || session::replaying() || defer_fd(config, &query) {
+        return Ok(None);
+    }
+    start_fd(config, &finder_mode(config, config.no_cwd_filter, false))
+}
+
+fn run_finder_loop(config: &Config, mut first_fd: Option<duct::ReaderHandle>) -> Result<()> {
+    let mut global_history = config.no_cwd_filter;
+    let mut fd_hidden_files = false;
+    let mut fd_requested = false;
+    let mut previous_query = config.query.clone().unwrap_or_default();
+    cap_query_len(config, &mut previous_query);
+    let mut recorder = match &config.record_session {
+        Some(path) => Some(session::Recorder::new(path, file_history_bytes()?)?),
+        None => None,
+    };
+    let mut pass_number = 0;
+    loop {
+        let mode = finder_mode(config, global_history, fd_hidden_files);
+        let early_fd = first_fd.take();
+
+        let fd_deferred = !fd_requested && defer_fd(config, &previous_query);
+
+        // --dry-run prints the commands for the first mode and stops there,
+        // before anything gets spawned.
+        if config.dry_run {
+            if config.stdin {
+                eprintln!("fd: not run, reading candidates from stdin");
+            } else if fd_deferred {
+                eprintln!("fd: not run, the query is shorter than --min-query-len");
+            } else {
+                eprintln!("fd args: {:?}", fd_args(config, &mode));
+            }
+            if config.builtin {
+                eprintln!("fzf: not run, using the builtin matcher");
+            } else {
+                eprintln!(
+                    "fzf command: {:?}",
+                    fzf_command(config, &mode, &previous_query)?
+                );
+            }
+            return Ok(());
+        }
+
+        let replay = session::replay_pass(pass_number, mode.mode_name)?;
+        pass_number += 1;
+        let (fd_cache, fd_reader) = if fd_deferred || replay.is_some() {
+            (None, None)
+        } else {
+            let fd_cache = open_fd_cache(config, &mode)?;
+            match early_fd {
+                Some(reader) => (fd_cache, Some(reader)),
+                None => (fd_cache, start_fd(config, &mode)?),
+            }
+        };
+        let (fzf_status, fzf_output, counts, candidates) = run_finder_once(
+            config,
+            &mode,
+            fd_reader,
+            fd_cache.as_ref(),
+            replay,
+            &previous_query,
+        )?;
+        let source = if replay.is_some() {
+            "the replayed session"
+        } else if config.stdin {
+            "stdin"
+        } else if fd_deferred {
+            "fd (not run)"
+        } else if fd_cache.as_ref().and_then(cache::FdCache::cached).is_some() {
+            "the fd cache"
+        } else {
+            "fd"
+        };
+        if config.verbose {
+            eprintln!(
+                "founder: {} candidates from history, {} from {}",
+                counts.history, counts.fd, source,
+            );
+        }
+
+        // The first line of output is the query string, the second is the
+        // selection key (empty for Enter, or one of EXPECT_KEYS), and the
+        // third line is the selection (possibly empty with an accompanying
+        // error status). Note that these split components will not include
+        // trailing newlines. With --null, the "lines" are NUL-terminated.
+        //
+        // An old fzf without --print-query or --expect exits with an error
+        // and prints nothing, and fzf might also print nothing when it's
+        // aborted. Treat missing lines as empty in those cases, so that we
+        // exit with fzf's status below. A success status without all three
+        // lines is more confusing, and we don't want to guess at a selection.
+        let separator = record_separator(config);
+        let mut parts = fzf_output.split(|&byte| byte == separator);
+        let (query_line, key, selection_line) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(query), Some(key), Some(selection)) => (query, key, selection),
+            (query, key, _) if !fzf_status.success() => {
+                (query.unwrap_or(b""), key.unwrap_or(b""), &b""[..])
+            }
+            _ => bail!(
+                "unexpected output from fzf, founder needs --print-query and \
+                 --expect support (try upgrading fzf)"
+            ),
+        };
+        if let Some(recorder) = &mut recorder {
+            recorder.record_pass(session::RecordedPass {
+                mode: mode.mode_name,
+                query: &previous_query,
+                source,
+                candidates: &candidates,
+                separator,
+                fzf_status,
+                fzf_query: query_line,
+                key,
+                selection: selection_line,
+            })?;
+        }
+        let used_query = OsStr::from_bytes(query_line);
+        let selection = expand_selection(
+            &strip_ansi(config, strip_annotation(config, selection_line)),
+            home_dir(),
+        )?;
+
+        // Check the key before the status. The user may have a query that
+        // matches nothing, in which case a mode switch will lead to a non-zero
+        // status, which we ignore.
+        if key.is_empty() {
+            // With --print-query-only, Enter just prints the query, whether
+            // or not it matched anything, and nothing gets recorded. An abort
+            // or an error from fzf still exits the usual way.
+            if config.print_query_only {
+                let code = exit_code_for_fzf_status(fzf_status);
+                if !fzf_status.success() && code != EXIT_NO_MATCH {
+                    std::process::exit(code);
+                }
+                write_output(config, query_line)?;
+                return Ok(());
+            }
+
+            // This is the newline case, which means the user has made a
+            // selection. Record that selection to history, write it to
+            // stdout, and exit.
+
+            // If Fzf exited with an error code, we exit with the
+            // corresponding code from the contract at the top of this
+            // file. For example, we get an error code if the user's filter
+            // didn't match anything.
+            if !fzf_status.success() {
+                std::process::exit(exit_code_for_fzf_status(fzf_status));
+            }
+
+            // A no at the --confirm prompt goes back to the finder, in the
+            // same mode, with the same query.
+            if config.confirm && !confirm_selection(&selection)? {
+                if config.builtin {
+                    // The builtin matcher isn't interactive, so it would just
+                    // pick the same thing again.
+                    std::process::exit(EXIT_ABORTED);
+                }
+                previous_query.clear();
+                previous_query.push(used_query);
+                cap_query_len(config, &mut previous_query);
+                continue;
+            }
+
+            // Absolutify the selection and add that to the history file,
+            // unless --no-record is specified.
+            record_selection(config, &selection, query_line)?;
+            print_mode(config, &mode);
+
+            if let Some(command) = &config.on_select {
+                run_on_select(command, &selection)?;
+            }
+
+            // With --edit, open the selection instead of printing it.
+            if config.edit {
+                return open_in_editor(&selection);
+            }
+
+            // Write the selection to stdout. Add a newline to be
+            // compatible with FZF, unless --no-newline is specified. With
+            // --absolute, history entries under the cwd (which were shown
+            // relative) get absolutified again, as do fd results.
+            let mut output = if config.absolute {
+                history::absolute_path(&selection)?.as_bytes().to_vec()
+            } else {
+                selection
+            };
+            // With --dirname, print the directory containing the selection.
+            // The history still gets the file itself.
+            if config.dirname {
+                output = parent_dir(&output).as_os_str().as_bytes().to_vec();
+            }
+            // With --json, wrap everything up in an object instead. JSON
+            // strings have to be Unicode, so non-UTF-8 paths are lossy
+            // here.
+            if config.json {
+                let object = serde_json::json!({
+                    "selection": String::from_utf8_lossy(&output),
+                    "query": used_query.to_string_lossy(),
+                    "mode": mode.mode_name,
+                    "key": "enter",
+                });
+                output = object.to_string().into_bytes();
+            }
+            write_output(config, &output)?;
+            return Ok(());
+        }
+        let action = EXPECT_KEYS
+            .iter()
+            .find(|&&(name, _)| name.as_bytes() == key)
+            .map(|&(_, action)| action);
+        match action {
+            Some(KeyAction::ToggleHistoryScope) => {
+                // The user pressed Ctrl-T. We switch the history scope,
+                // preserving the query string, and repeat this loop. If fd
+                // didn't run because of --min-query-len, we stay in this mode
+                // and run it now.
+                if fd_deferred {
+                    fd_requested = true;
+                } else {
+                    global_history = !global_history;
+                }
+                previous_query.clear();
+                previous_query.push(used_query);
+                cap_query_len(config, &mut previous_query);
+            }
+            Some(KeyAction::ToggleHidden) => {
+                // The user pressed Alt-H. Same as above, but for fd's hidden
+                // files, and a deferred fd runs with the new setting.
+                if fd_deferred {
+                    fd_requested = true;
+                }
+                fd_hidden_files = !fd_hidden_files;
+                previous_query.clear();
+                previous_query.push(used_query);
+                cap_query_len(config, &mut previous_query);
+            }
+            Some(KeyAction::OpenParentDir) => {
+                // The user pressed Ctrl-O. This is a selection like Enter, so
+                // it's recorded the same way, but instead of printing it we
+                // open its directory in the file manager.
+                if !fzf_status.success() {
+                    std::process::exit(exit_code_for_fzf_status(fzf_status));
+                }
+                if config.confirm && !confirm_selection(&selection)? {
+                    previous_query.clear();
+                    previous_query.push(used_query);
+                    cap_query_len(config, &mut previous_query);
+                    continue;
+                }
+                record_selection(config, &selection, query_line)?;
+                print_mode(config, &mode);
+                return open_parent_dir(&selection);
+            }
+            None => panic!(
+                "unexpected selector key: {:?}",
+                String::from_utf8_lossy(key)
+            ),
+        }
+    }
+}
+
+// The values that fd accepts for --type, both short and long, plus "all".
+const FD_TYPES: &[&str] = &[
+    "all",
+    "f",
+    "file",
+    "d",
+    "directory",
+    "l",
+    "symlink",
+    "x",
+    "executable",
+    "e",
+    "empty",
+    "s",
+    "socket",
+    "p",
+    "pipe",
+];
+
+fn validate_positive_integer(value: String) -> Result<(), String> {
+    match value.parse::<usize>() {
+        Ok(n) if n > 0 => Ok(()),
+        _ => Err("must be a positive integer".into()),
+    }
+}
+
+// fzf takes --height as lines or a percentage, optionally with a leading ~ to
+// shrink to fit the input. We check that loosely and leave the rest to fzf.
+fn validate_height(value: String) -> Result<(), String> {
+    let digits = value.strip_prefix('~').unwrap_or(&value);
+    let digits = digits.strip_suffix('%').unwrap_or(digits);
+    if !digits.is_empty() && digits.bytes().all(|byte| byte.is_ascii_digit()) {
+        Ok(())
+    } else {
+        Err("must be a line count or a percentage, like 20 or 40%".into())
+    }
+}
+
+fn validate_retain_fraction(value: String) -> Result<(), String> {
+    match value.parse::<f64>() {
+        Ok(fraction) if fraction > 0.0 && fraction <= 1.0 => Ok(()),
+        _ => Err("must be a number greater than 0 and at most 1".into()),
+    }
+}
+
+fn clap_parse_argv() -> clap::ArgMatches<'static> {
+    App::new("founder")
+        .arg(Arg::with_name("absolute").long("absolute"))
+        .arg(Arg::with_name("ansi").long("ansi"))
+        .arg(
+            Arg::with_name("bind")
+                .long("bind")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("branch-aware")
+                .long("branch-aware")
+                .global(true),
+        )
+        .arg(Arg::with_name("builtin").long("builtin"))
+        .arg(Arg::with_name("cache").long("cache"))
+        .arg(
+            Arg::with_name("cache-ttl")
+                .long("cache-ttl")
+                .takes_value(true)
+                .requires("cache")
+                .validator(validate_positive_integer),
+        )
+        .arg(
+            Arg::with_name("color")
+                .long("color")
+                .overrides_with("no-color"),
+        )
+        .arg(
+            Arg::with_name("no-color")
+                .long("no-color")
+                .overrides_with("color"),
+        )
+        .arg(
+            Arg::with_name("compact-retain-fraction")
+                .long("compact-retain-fraction")
+                .takes_value(true)
+                .global(true)
+                .validator(validate_retain_fraction),
+        )
+        .arg(Arg::with_name("confirm").long("confirm"))
+        .arg(Arg::with_name("cycle").long("cycle"))
+        .arg(
+            Arg::with_name("depth")
+                .long("depth")
+                .takes_value(true)
+                .validator(validate_positive_integer),
+        )
+        .arg(Arg::with_name("dirname").long("dirname"))
+        .arg(Arg::with_name("dry-run").long("dry-run"))
+        .arg(Arg::with_name("edit").long("edit"))
+        .arg(Arg::with_name("exact").long("exact"))
+        .arg(
+            Arg::with_name("exclude")
+                .long("exclude")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("fd-glob")
+                .long("fd-glob")
+                .takes_value(true)
+                .conflicts_with("fd-regex"),
+        )
+        .arg(
+            Arg::with_name("fd-regex")
+                .long("fd-regex")
+                .takes_value(true),
+        )
+        .group(ArgGroup::with_name("fd-pattern").args(&["fd-glob", "fd-regex"]))
+        .arg(
+            Arg::with_name("fd-threads")
+                .long("fd-threads")
+                .takes_value(true)
+                .validator(validate_positive_integer),
+        )
+        .arg(
+            Arg::with_name("fd-timeout")
+                .long("fd-timeout")
+                .takes_value(true)
+                .validator(validate_positive_integer),
+        )
+        .arg(
+            Arg::with_name("fd-type")
+                .long("fd-type")
+                .alias("type")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .possible_values(FD_TYPES),
+        )
+        .arg(
+            Arg::with_name("filter-history")
+                .long("filter-history")
+                .requires("fd-pattern"),
+        )
+        .arg(
+            Arg::with_name("finder")
+                .long("finder")
+                .takes_value(true)
+                .global(true)
+                .possible_values(&["fzf", "skim", "sk"]),
+        )
+        .arg(Arg::with_name("follow").long("follow"))
+        .arg(
+            Arg::with_name("fzf-history-size")
+                .long("fzf-history-size")
+                .takes_value(true)
+                .validator(validate_positive_integer),
+        )
+        .arg(
+            Arg::with_name("height")
+                .long("height")
+                .takes_value(true)
+                .validator(validate_height),
+        )
+        .arg(
+            Arg::with_name("history-file")
+                .long("history-file")
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("history-null")
+                .long("history-null")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("ignore-case-paths")
+                .long("ignore-case-paths")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("ignore-file")
+                .long("ignore-file")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(Arg::with_name("json").long("json"))
+        .arg(
+            Arg::with_name("keep-duplicates")
+                .long("keep-duplicates")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("layout")
+                .long("layout")
+                .takes_value(true)
+                .possible_values(&["default", "reverse", "reverse-list"]),
+        )
+        .arg(
+            Arg::with_name("log-file")
+                .long("log-file")
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(Arg::with_name("log-selections").long("log-selections"))
+        .arg(Arg::with_name("match-basename").long("match-basename"))
+        .arg(
+            Arg::with_name("max-history-bytes")
+                .long("max-history-bytes")
+                .takes_value(true)
+                .global(true)
+                .validator(validate_positive_integer),
+        )
+        .arg(
+            Arg::with_name("max-query-len")
+                .long("max-query-len")
+                .takes_value(true)
+                .validator(validate_positive_integer),
+        )
+        .arg(
+            Arg::with_name("max-results")
+                .long("max-results")
+                .takes_value(true)
+                .validator(validate_positive_integer),
+        )
+        .arg(
+            Arg::with_name("min-query-len")
+                .long("min-query-len")
+                .takes_value(true)
+                .validator(validate_positive_integer),
+        )
+        .arg(Arg::with_name("no-compact").long("no-compact").global(true))
+        .arg(Arg::with_name("no-cwd-filter").long("no-cwd-filter"))
+        .arg(Arg::with_name("no-history").long("no-history"))
+        .arg(Arg::with_name("no-ignore").long("no-ignore"))
+        .arg(Arg::with_name("no-mouse").long("no-mouse"))
+        .arg(Arg::with_name("no-newline").long("no-newline"))
+        .arg(
+            Arg::with_name("no-normalize")
+                .long("no-normalize")
+                .global(true)
+                .conflicts_with("resolve-symlinks"),
+        )
+        .arg(Arg::with_name("no-record").long("no-record"))
+        .arg(Arg::with_name("no-sort").long("no-sort"))
+        .arg(Arg::with_name("null").long("null"))
+        .arg(
+            Arg::with_name("on-select")
+                .long("on-select")
+                .takes_value(true)
+                .allow_hyphen_values(true),
+        )
+        .arg(
+            Arg::with_name("order")
+                .long("order")
+                .takes_value(true)
+                .possible_values(&["history-first", "fd-first", "interleaved"]),
+        )
+        .arg(
+            Arg::with_name("path")
+                .long("path")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(Arg::with_name("print-mode").long("print-mode"))
+        .arg(Arg::with_name("print-query-only").long("print-query-only"))
+        .arg(
+            Arg::with_name("profile")
+                .long("profile")
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("prompt")
+                .long("prompt")
+                .takes_value(true)
+                .allow_hyphen_values(true),
+        )
+        .arg(
+            Arg::with_name("query")
+                .long("query")
+                .takes_value(true)
+                .allow_hyphen_values(true),
+        )
+        .arg(
+            Arg::with_name("query-prefix")
+                .long("query-prefix")
+                .takes_value(true)
+                .allow_hyphen_values(true),
+        )
+        .arg(
+            Arg::with_name("record-session")
+                .long("record-session")
+                .takes_value(true),
+        )
+        .arg(Arg::with_name("relative").long("relative"))
+        .arg(
+            Arg::with_name("replay-session")
+                .long("replay-session")
+                .takes_value(true)
+                .conflicts_with("record-session"),
+        )
+        .arg(
+            Arg::with_name("resolve-symlinks")
+                .long("resolve-symlinks")
+                .global(true),
+        )
+        .arg(Arg::with_name("show-age").long("show-age"))
+        .arg(Arg::with_name("sort-by-path").long("sort-by-path"))
+        .arg(Arg::with_name("stdin").long("stdin"))
+        .arg(
+            Arg::with_name("tiebreak")
+                .long("tiebreak")
+                .takes_value(true)
+                .possible_values(&["frecency", "length", "chunk", "begin", "end", "index"]),
+        )
+        .arg(
+            Arg::with_name("tmux")
+                .long("tmux")
+                .overrides_with("no-tmux"),
+        )
+        .arg(
+            Arg::with_name("no-tmux")
+                .long("no-tmux")
+                .overrides_with("tmux"),
+        )
+        .arg(
+            Arg::with_name("tmux-opts")
+                .long("tmux-opts")
+                .takes_value(true)
+                .allow_hyphen_values(true),
+        )
+        .arg(Arg::with_name("verbose").long("verbose"))
+        .arg(Arg::with_name("version").long("version"))
+        .subcommand(
+            SubCommand::with_name("add").arg(Arg::with_name("path").index(1).required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("bench")
+                .setting(AppSettings::Hidden)
+                .arg(Arg::with_name("compaction").long("compaction"))
+                .arg(
+                    Arg::with_name("display-paths")
+                        .long("display-paths")
+                        .conflicts_with("compaction"),
+                )
+                .arg(
+                    Arg::with_name("files")
+                        .long("files")
+                        .takes_value(true)
+                        .validator(validate_positive_integer),
+                )
+                .arg(
+                    Arg::with_name("lines")
+                        .long("lines")
+                        .takes_value(true)
+                        .validator(validate_positive_integer),
+                )
+                .arg(
+                    Arg::with_name("runs")
+                        .long("runs")
+                        .takes_value(true)
+                        .validator(validate_positive_integer),
+                ),
+        )
+        .subcommand(SubCommand::with_name("compact"))
+        .subcommand(SubCommand::with_name("doctor"))
+        .subcommand(SubCommand::with_name("import").arg(Arg::with_name("file").index(1)))
+        .subcommand(
+            SubCommand::with_name("init").arg(
+                Arg::with_name("shell")
+                    .index(1)
+                    .required(true)
+                    .possible_values(init::SHELLS),
+            ),
+        )
+        .get_matches()
+}
+
+// Tests start from the Default and set what they need. Outside of tests, every
+// field comes from the arguments in run().
+#[cfg_attr(test, derive(Default))]
+struct Config {
+    absolute: bool,
+    // Only set when --ansi is given and we're coloring (see use_color), and
+    // never with --builtin, which would match against the color codes.
+    ansi: bool,
+    bind: Vec<String>,
+    branch_aware: bool,
+    builtin: bool,
+    cache: bool,
+    cache_ttl: Duration,
+    color: bool,
+    compact_retain_fraction: f64,
+    confirm: bool,
+    cycle: bool,
+    depth: Option<usize>,
+    dirname: bool,
+    dry_run: bool,
+    edit: bool,
+    exact: bool,
+    excludes: Vec<String>,
+    fd_glob: Option<String>,
+    fd_regex: Option<String>,
+    fd_threads: Option<usize>,
+    fd_timeout: Option<Duration>,
+    // Replaces the default --type=f when non-empty.
+    fd_types: Vec<String>,
+    filter_history: bool,
+    finder: Finder,
+    follow: bool,
+    fzf_bin: Option<OsString>,
+    fzf_history_size: usize,
+    height: Option<String>,
+    history_null: bool,
+    ignore_case_paths: bool,
+    ignore_files: Vec<PathBuf>,
+    json: bool,
+    keep_duplicates: bool,
+    layout: Option<String>,
+    log_selections: bool,
+    match_basename: bool,
+    max_history_bytes: Option<u64>,
+    max_query_len: Option<usize>,
+    max_results: Option<usize>,
+    min_query_len: Option<usize>,
+    no_compact: bool,
+    no_cwd_filter: bool,
+    no_history: bool,
+    no_ignore: bool,
+    no_mouse: bool,
+    no_newline: bool,
+    no_normalize: bool,
+    no_record: bool,
+    no_sort: bool,
+    null: bool,
+    on_select: Option<OsString>,
+    order: Order,
+    // Absolute, from --path. Empty means fd searches the cwd.
+    paths: Vec<PathBuf>,
+    print_mode: bool,
+    print_query_only: bool,
+    prompt: Option<String>,
+    query: Option<OsString>,
+    query_prefix: Option<String>,
+    record_session: Option<PathBuf>,
+    relative: bool,
+    resolve_symlinks: bool,
+    show_age: bool,
+    sort_by_path: bool,
+    stdin: bool,
+    // Either "frecency", which is ours, or passed through to fzf.
+    tiebreak: Option<String>,
+    tmux: bool,
+    // Split on whitespace, e.g. "-p 80%,60%".
+    tmux_opts: Vec<String>,
+    verbose: bool,
+}
+
+fn main() {
+    // Exit with EXIT_ERROR rather than the default 1 for errors, because 1
+    // means that nothing matched.
+    if let Err(e) = run() {
+        eprintln!("Error: {:?}", e);
+        std::process::exit(EXIT_ERROR);
+    }
+}
+
+// Logging is off unless $FOUNDER_LOG is set, using env_logger's RUST_LOG
+// syntax, like FOUNDER_LOG=debug or FOUNDER_LOG=founder=trace. With --log-file,
+// log lines are appended to that file instead of going to stderr, and the level
+// defaults to debug for founder itself. Nothing is ever logged to stdout,
+// which is for the selection.
+fn init_logging(matches: &clap::ArgMatches) -> Result<()> {
+    let log_file = matches.value_of_os("log-file");
+    let mut builder = env_logger::Builder::new();
+    match env::var("FOUNDER_LOG") {
+        Ok(filters) => builder.parse_filters(&filters),
+        Err(_) if log_file.is_some() => builder.filter_module("founder", log::LevelFilter::Debug),
+        Err(_) => return Ok(()),
+    };
+    if let Some(path) = log_file {
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("failed to open log file {:?}", path))?;
+        builder.target(env_logger::Target::Pipe(Box::new(file)));
+    }
+    // Several founders can share a log file, so each line has the PID.
+    builder.format(|buf, record| {
+        writeln!(
+            buf,
+            "{} [{}] {} {}: {}",
+            history::unix_now(),
+            std::process::id(),
+            record.level(),
+            record.target(),
+            record.args()
+        )
+    });
+    builder.init();
+    Ok(())
+}
+
+// The --ignore-file paths, made absolute like the --path roots. A missing
+// file is an error here, before fzf starts, rather than a warning from fd
+// that's hidden behind the finder.
+fn ignore_file_paths(matches: &clap::ArgMatches) -> Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    for path in matches.values_of_os("ignore-file").into_iter().flatten() {
+        let path = PathBuf::from(history::absolute_path(path.as_bytes())?);
+        if !path.is_file() {
+            bail!("--ignore-file {} doesn't exist", path.display());
+        }
+        paths.push(path);
+    }
+    Ok(paths)
+}
+
+// Whether to use fzf-tmux instead of fzf. An explicit --tmux or --no-tmux wins,
+// then FOUNDER_TMUX=1 or FOUNDER_TMUX=0, and otherwise we use fzf-tmux
+// whenever we're running inside tmux. Outside of tmux ($TMUX unset) we never
+// use fzf-tmux, even if it was requested, so that scripts that always pass
+// --tmux keep working.
+fn use_tmux(matches: &clap::ArgMatches) -> Result<bool> {
+    let requested = if matches.is_present("tmux") {
+        true
+    } else if matches.is_present("no-tmux") {
+        false
+    } else {
+        match env::var_os("FOUNDER_TMUX") {
+            Some(value) if value == "1" => true,
+            Some(value) if value == "0" => false,
+            Some(value) => bail!("FOUNDER_TMUX must be 0 or 1, found {:?}", value),
+            None => true,
+        }
+    };
+    let in_tmux = env::var_os("TMUX").is_some_and(|value| !value.is_empty());
+    Ok(requested && in_tmux)
+}
+
+// Every color decision goes through here, and the result ends up in
+// config.color. --color and --no-color override each other. Without either, a
+// non-empty NO_COLOR turns color off (see no-color.org), and otherwise we
+// color only when stdout is a terminal.
+fn use_color(matches: &clap::ArgMatches) -> bool {
+    if matches.is_present("color") {
+        true
+    } else if matches.is_present("no-color")
+        || env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty())
+    {
+        false
+    } else {
+        // SAFETY: isatty has no preconditions.
+        unsafe { libc::isatty(libc::STDOUT_FILENO) == 1 }
+    }
+}
+
+fn run() -> Result<()> {
+    history::install_signal_handlers()?;
+    let matches = clap_parse_argv();
+    init_logging(&matches)?;
+    // init just prints a script. It doesn't need the history or a finder.
+    if let Some(init_matches) = matches.subcommand_matches("init") {
+        print!("{}", init::script(init_matches.value_of("shell").unwrap()));
+        return Ok(());
+    }
+    // This needs to come before anything touches the history.
+    if let Some(history_file) = matches.value_of_os("history-file") {
+        set_file_history_path_override(history_file)?;
+    }
+    if let Some(profile) = matches.value_of("profile") {
+        set_profile(profile)?;
+    }
+    // The replay changes to the recorded cwd, so this comes before anything
+    // resolves a relative path.
+    if let Some(session_path) = matches.value_of_os("replay-session") {
+        if let Some(subcommand) = matches.subcommand_name() {
+            bail!(
+                "--replay-session only replays the finder, not {}",
+                subcommand
+            );
+        }
+        session::start_replay(Path::new(session_path))?;
+    }
+    let finder = finder(&matches)?;
+    let color = use_color(&matches);
+    let config = Config {
+        absolute: matches.is_present("absolute"),
+        ansi: matches.is_present("ansi") && color && !matches.is_present("builtin"),
+        bind: fzf_binds(&matches),
+        branch_aware: matches.is_present("branch-aware"),
+        builtin: matches.is_present("builtin"),
+        cache: matches.is_present("cache"),
+        cache_ttl: match matches.value_of("cache-ttl") {
+            Some(secs) => Duration::from_secs(secs.parse().unwrap()), // already validated
+            None => DEFAULT_CACHE_TTL,
+        },
+        color,
+        compact_retain_fraction: match matches.value_of("compact-retain-fraction") {
+            Some(fraction) => fraction.parse().unwrap(), // already validated
+            None => DEFAULT_COMPACT_RETAIN_FRACTION,
+        },
+        confirm: matches.is_present("confirm"),
+        cycle: matches.is_present("cycle"),
+        depth: matches.value_of("depth").map(|d| d.parse().unwrap()), // already validated
+        dirname: matches.is_present("dirname"),
+        dry_run: matches.is_present("dry-run"),
+        edit: matches.is_present("edit"),
+        exact: matches.is_present("exact"),
+        excludes: matches
+            .values_of("exclude")
+            .map(|values| values.map(String::from).collect())
+            .unwrap_or_default(),
+        fd_glob: matches.value_of("fd-glob").map(String::from),
+        fd_regex: matches.value_of("fd-regex").map(String::from),
+        fd_threads: matches.value_of("fd-threads").map(|n| n.parse().unwrap()), // already validated
+        fd_timeout: matches
+            .value_of("fd-timeout")
+            .map(|secs| Duration::from_secs(secs.parse().unwrap())), // already validated
+        fd_types: matches
+            .values_of("fd-type")
+            .map(|values| values.map(String::from).collect())
+            .unwrap_or_default(),
+        filter_history: matches.is_present("filter-history"),
+        finder,
+        follow: matches.is_present("follow"),
+        fzf_bin: env::var_os("FOUNDER_FZF_BIN").filter(|value| !value.is_empty()),
+        fzf_history_size: match matches.value_of("fzf-history-size") {
+            Some(n) => n.parse().unwrap(), // already validated
+            None => DEFAULT_FZF_HISTORY_SIZE,
+        },
+        height: matches.value_of("height").map(String::from),
+        history_null: matches.is_present("history-null"),
+        ignore_case_paths: matches.is_present("ignore-case-paths"),
+        ignore_files: ignore_file_paths(&matches)?,
+        json: matches.is_present("json"),
+        keep_duplicates: matches.is_present("keep-duplicates"),
+        layout: matches.value_of("layout").map(String::from),
+        log_selections: matches.is_present("log-selections"),
+        match_basename: matches.is_present("match-basename"),
+        max_history_bytes: matches
+            .value_of("max-history-bytes")
+            .map(|n| n.parse().unwrap()), // already validated
+        max_query_len: matches
+            .value_of("max-query-len")
+            .map(|n| n.parse().unwrap()), // already validated
+        max_results: matches.value_of("max-results").map(|n| n.parse().unwrap()), // already validated
+        min_query_len: matches
+            .value_of("min-query-len")
+            .map(|n| n.parse().unwrap()), // already validated
+        // A replay leaves the real history alone.
+        no_compact: matches.is_present("no-compact") || session::replaying(),
+        no_cwd_filter: matches.is_present("no-cwd-filter"),
+        no_history: matches.is_present("no-history"),
+        no_ignore: matches.is_present("no-ignore"),
+        no_mouse: matches.is_present("no-mouse"),
+        no_newline: matches.is_present("no-newline"),
+        no_normalize: matches.is_present("no-normalize"),
+        no_record: matches.is_present("no-record") || session::replaying(),
+        no_sort: matches.is_present("no-sort"),
+        null: matches.is_present("null"),
+        on_select: matches.value_of_os("on-select").map(OsString::from),
+        order: matches
+            .value_of("order")
+            .map_or(Order::HistoryFirst, |name| Order::from_name(name).unwrap()), // already validated
+        paths: matches
+            .values_of_os("path")
+            .into_iter()
+            .flatten()
+            .map(|path| Ok(PathBuf::from(history::absolute_path(path.as_bytes())?)))
+            .collect::<Result<_>>()?,
+        print_mode: matches.is_present("print-mode"),
+        print_query_only: matches.is_present("print-query-only"),
+        prompt: matches.value_of("prompt").map(String::from),
+        query: matches.value_of_os("query").map(OsString::from),
+        query_prefix: matches.value_of("query-prefix").map(String::from),
+        record_session: matches.value_of_os("record-session").map(PathBuf::from),
+        relative: matches.is_present("relative"),
+        resolve_symlinks: matches.is_present("resolve-symlinks"),
+        show_age: matches.is_present("show-age"),
+        sort_by_path: matches.is_present("sort-by-path"),
+        stdin: matches.is_present("stdin"),
+        tiebreak: matches.value_of("tiebreak").map(String::from),
+        tmux: use_tmux(&matches)? && finder == Finder::Fzf,
+        tmux_opts: matches
+            .value_of("tmux-opts")
+            .map(|opts| opts.split_whitespace().map(String::from).collect())
+            .unwrap_or_default(),
+        verbose: matches.is_present("verbose"),
+    };
+    let first_fd = if matches.subcommand_name().is_none() && !matches.is_present("version") {
+        start_first_fd(&config)?
+    } else {
+        None
+    };
+    if !config.tmux && !config.tmux_opts.is_empty() {
+        eprintln!("founder: --tmux-opts has no effect without --tmux");
+    }
+    if config.verbose {
+        match (history_dir()?, history_dir_source()?) {
+            (Some(dir), Some(source)) => {
+                eprintln!("founder: history dir {} (from {})", dir.display(), source)
+            }
+            _ => eprintln!("founder: no history dir"),
+        }
+    }
+    if matches.is_present("version") {
+        print_versions(&config)?;
+        return Ok(());
+    }
+    // bench has its own history, and it never records or compacts.
+    if let Some(bench_matches) = matches.subcommand_matches("bench") {
+        return bench::bench(config, bench_matches);
+    }
+    crossbeam_utils::thread::scope(|scope| {
+        // import compacts after it's done, and it would race with the
+        // background compaction's rename otherwise, so it skips this one. So
+        // does compact, which compacts in the foreground. --no-compact skips
+        // it for everything, for callers that would rather run compact now
+        // and then than pay for the extra I/O on every run.
+        let import_matches = matches.subcommand_matches("import");
+        let compact_subcommand = matches.subcommand_matches("compact").is_some();
+        let background_compaction =
+            import_matches.is_none() && !compact_subcommand && !config.no_compact;
+        let compactor_thread = if background_compaction {
+            Some(scope.spawn(|_| compact_history(&config)))
+        } else {
+            None
+        };
+        let command_result = if let Some(add_matches) = matches.subcommand_matches("add") {
+            let path = add_matches.value_of_os("path").unwrap().as_bytes();
+            add_to_history(&config, path)
+        } else if let Some(import_matches) = import_matches {
+            import_history(&config, import_matches.value_of_os("file"))
+        } else if compact_subcommand {
+            compact_command(&config)
+        } else if matches.subcommand_matches("doctor").is_some() {
+            doctor(&config)
+        } else {
+            run_finder_loop(&config, first_fd)
+        };
+        let compactor_result = match compactor_thread {
+            Some(thread) => thread.join().expect("compactor panic").map(drop),
+            None => Ok(()),
+        };
+        command_result.and(compactor_result.map_err(Into::into))
+    })
+    .expect("panic in threading scope")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn display(path: &str, cwd: &str, home: &str) -> String {
+        display_path(
+            Path::new(path),
+            Path::new(cwd),
+            Some(Path::new(home)),
+            false,
+        )
+        .unwrap()
+        .to_string_lossy()
+        .into_owned()
+    }
+
+    // Selections come back from fzf as display_path() showed them, and
+    // expand_selection() has to give us the same file again. Relative paths
+    // are relative to the cwd, and a leading ./ or the ../ from --relative
+    // doesn't change which file that is, so compare them joined onto the cwd.
+    // None of these paths are real, so there are no symlinks to worry about.
+    fn assert_round_trip(path: &str, cwd: &str, home: &str, relative: bool) {
+        let (cwd, home) = (Path::new(cwd), Path::new(home));
+        let shown = display_path(Path::new(path), cwd, Some(home), relative).unwrap();
+        let expanded = expand_selection(shown.as_os_str().as_bytes(), Some(home)).unwrap();
+        let lexical = |path: PathBuf| {
+            let mut normal = PathBuf::new();
+            for component in path.components() {
+                match component {
+                    Component::ParentDir => assert!(normal.pop()),
+                    component => normal.push(component),
+                }
+            }
+            normal
+        };
+        assert_eq!(
+            lexical(cwd.join(OsStr::from_bytes(&expanded))),
+            lexical(cwd.join(path)),
+            "{:?} was shown as {:?}",
+            path,
+            shown,
+        );
+    }
+
+    #[test]
+    fn test_display_path_round_trips() {
+        let cases = [
+            // A file named ~, and a directory named ~, in the cwd.
+            "~",
+            "~/a.txt",
+            "/home/me/project/~",
+            "/home/me/project/~/a.txt",
+            // A relative ~foo isn't the home directory of a user named foo.
+            "~foo",
+            "~root/a.txt",
+            // Home itself, paths under it, and a ~ in the middle of one.
+            "/home/me",
+            "/home/me/notes.txt",
+            "/home/me/a/~/b.txt",
+            "/home/me/~foo",
+            // Outside of home.
+            "/etc/hosts",
+            "/srv/~/a.txt",
+            "/~root/a.txt",
+            "/",
+        ];
+        for path in &cases {
+            for &relative in &[false, true] {
+                assert_round_trip(path, "/home/me/project", "/home/me", relative);
+                assert_round_trip(path, "/srv/www", "/home/me", relative);
+            }
+        }
+    }
+
+    #[test]
+    fn test_display_path_round_trips_with_home_at_the_root() {
+        for path in &["/", "/etc/hosts", "/~root/a.txt", "~", "~foo", "a/~/b.txt"] {
+            for &relative in &[false, true] {
+                assert_round_trip(path, "/srv/www", "/", relative);
+            }
+        }
+        assert_eq!(display("/etc/hosts", "/srv/www", "/"), "~/etc/hosts");
+    }
+
+    fn expand(selection: &str, home: &str) -> String {
+        let expanded = expand_selection(selection.as_bytes(), Some(Path::new(home))).unwrap();
+        String::from_utf8(expanded).unwrap()
+    }
+
+    #[test]
+    fn test_expand_selection() {
+        assert_eq!(expand("~", "/home/me"), "/home/me");
+        assert_eq!(expand("~/", "/home/me"), "/home/me");
+        assert_eq!(expand("~/a.txt", "/home/me"), "/home/me/a.txt");
+        assert_eq!(expand("~", "/"), "/");
+        assert_eq!(expand("~/a.txt", "/"), "/a.txt");
+        // Without a home directory, ~ stays as it is.
+        assert_eq!(expand_selection(b"~/a.txt", None).unwrap(), b"~/a.txt");
+        // root is in the password database everywhere, but its home isn't
+        // always /root.
+        let root_home = user_home_dir(OsStr::new("root")).unwrap();
+        assert_eq!(expand("~root", "/home/me"), root_home.to_str().unwrap());
+        assert_eq!(
+            expand("~root/a.txt", "/home/me"),
+            root_home.join("a.txt").to_str().unwrap()
+        );
+        // Unknown users, and anything that isn't a leading ~ component, are
+        // left alone.
+        assert_eq!(
+            expand("~founder-nonexistent/a.txt", "/home/me"),
+            "~founder-nonexistent/a.txt"
+        );
+        assert_eq!(expand("./~/a.txt", "/home/me"), "./~/a.txt");
+        assert_eq!(expand("a/~/b.txt", "/home/me"), "a/~/b.txt");
+        assert_eq!(expand("/~/a.txt", "/home/me"), "/~/a.txt");
+    }
+
+    #[test]
+    fn test_escape_leading_tilde() {
+        let escape = |path: &str| escape_leading_tilde(Path::new(path));
+        assert_eq!(escape("~"), Path::new("./~"));
+        assert_eq!(escape("~/a.txt"), Path::new("./~/a.txt"));
+        assert_eq!(escape("~foo.txt"), Path::new("./~foo.txt"));
+        assert_eq!(escape("a/~foo.txt"), Path::new("a/~foo.txt"));
+        assert_eq!(escape("/~foo.txt"), Path::new("/~foo.txt"));
+        // A real file named ~foo.txt comes back from fzf escaped, and then it
+        // doesn't look like a user's home directory.
+        let escaped = escape("~foo.txt");
+        assert_eq!(expand(escaped.to_str().unwrap(), "/home/me"), "./~foo.txt");
+    }
+
+    // Runs the input thread on these candidates and returns what it would've
+    // written to fzf.
+    fn fzf_input(config: &Config, candidates: &[u8]) -> Vec<u8> {
+        let (mut fzf_stdin_reader, fzf_stdin_writer) = os_pipe::pipe().unwrap();
+        let mode = finder_mode(config, false, false);
+        let mut counts = CandidateCounts::default();
+        input_thread_inner(candidates, fzf_stdin_writer, config, &mode, &mut counts).unwrap();
+        let mut output = Vec::new();
+        fzf_stdin_reader.read_to_end(&mut output).unwrap();
+        output
+    }
+
+    #[test]
+    fn test_null_candidates_with_a_newline() {
+        let config = Config {
+            no_history: true,
+            null: true,
+            ..Config::default()
+        };
+        let candidates = b"a.txt\0two\nlines.txt\0b.txt";
+        assert_eq!(
+            fzf_input(&config, candidates),
+            b"a.txt\0two\nlines.txt\0b.txt\0"
+        );
+        // Without --null, the same bytes are newline-separated lines.
+        let config = Config {
+            no_history: true,
+            ..Config::default()
+        };
+        assert_eq!(
+            fzf_input(&config, candidates),
+            b"a.txt\0two\nlines.txt\0b.txt\n"
+        );
+    }
+
+    #[test]
+    fn test_display_path_of_the_cwd() {
+        // A history entry for the search directory itself is ., not an empty
+        // line, and it comes back from fzf as the same directory.
+        assert_eq!(display("/srv/www", "/srv/www", "/home/me"), ".");
+        assert_eq!(display("/home/me", "/home/me", "/home/me"), ".");
+        assert_eq!(display("/", "/", "/home/me"), ".");
+        assert_eq!(expand(".", "/home/me"), ".");
+        for &relative in &[false, true] {
+            assert_round_trip("/srv/www", "/srv/www", "/home/me", relative);
+        }
+    }
+
+    #[test]
+    fn test_display_path_with_cwd_at_home() {
+        assert_eq!(display("/home/me/a.txt", "/home/me", "/home/me"), "a.txt");
+        assert_eq!(
+            display("/home/me/sub/b.txt", "/home/me", "/home/me"),
+            "sub/b.txt"
+        );
+        assert_eq!(display("/etc/hosts", "/home/me", "/home/me"), "/etc/hosts");
+    }
+
+    #[test]
+    fn test_display_path_with_cwd_under_home() {
+        let cwd = "/home/me/project";
+        assert_eq!(display("/home/me/project/a.txt", cwd, "/home/me"), "a.txt");
+        assert_eq!(
+            display("/home/me/notes.txt", cwd, "/home/me"),
+            "~/notes.txt"
+        );
+        assert_eq!(display("/home/me", cwd, "/home/me"), "~");
+        assert_eq!(display("/etc/hosts", cwd, "/home/me"), "/etc/hosts");
+        // Relative paths from fd are already under the cwd.
+        assert_eq!(display("src/main.rs", cwd, "/home/me"), "src/main.rs");
+    }
+
+    #[test]
+    fn test_display_path_with_cwd_outside_home() {
+        let cwd = "/srv/www";
+        assert_eq!(
+            display("/srv/www/index.html", cwd, "/home/me"),
+            "index.html"
+        );
+        assert_eq!(
+            display("/home/me/notes.txt", cwd, "/home/me"),
+            "~/notes.txt"
+        );
+        assert_eq!(display("/srv/other.txt", cwd, "/home/me"), "/srv/other.txt");
+        // A path that only shares a string prefix with home isn't under it.
+        assert_eq!(
+            display("/home/meg/a.txt", cwd, "/home/me"),
+            "/home/meg/a.txt"
+        );
+    }
+
+    fn capped(max_query_len: Option<usize>, query: &str) -> String {
+        let config = Config {
+            max_query_len,
+            ..Config::default()
+        };
+        let mut query = OsString::from(query);
+        cap_query_len(&config, &mut query);
+        query.into_string().unwrap()
+    }
+
+    #[test]
+    fn test_cap_query_len() {
+        assert_eq!(capped(Some(3), "abcdef"), "abc");
+        // é is two bytes, and a cut between them drops the whole character.
+        assert_eq!(capped(Some(2), "aé"), "a");
+        assert_eq!(capped(Some(3), "aé"), "aé");
+        assert_eq!(capped(Some(1), "é"), "");
+        // Queries under the cap, and all queries without one, stay the same.
+        assert_eq!(capped(Some(10), "abcdef"), "abcdef");
+        assert_eq!(capped(Some(6), "abcdef"), "abcdef");
+        assert_eq!(capped(None, "abcdef"), "abcdef");
+    }
+
+    fn stripped(ansi: bool, line: &[u8]) -> Vec<u8> {
+        let config = Config {
+            ansi,
+            ..Config::default()
+        };
+        strip_ansi(&config, line).into_owned()
+    }
+
+    #[test]
+    fn test_strip_ansi() {
+        assert_eq!(stripped(true, b"\x1b[01;34msrc\x1b[0m/a.rs"), b"src/a.rs");
+        assert_eq!(stripped(true, b"\x1b[mplain.txt"), b"plain.txt");
+        // An ESC that doesn't start a CSI sequence is part of the path.
+        assert_eq!(stripped(true, b"a\x1bb.txt"), b"a\x1bb.txt");
+        assert_eq!(stripped(true, b"a.txt\x1b"), b"a.txt\x1b");
+        // A sequence cut off by the end of the line is dropped.
+        assert_eq!(stripped(true, b"a.txt\x1b[01"), b"a.txt");
+        // Without --ansi, nothing is stripped.
+        assert_eq!(
+            stripped(false, b"\x1b[01;34msrc\x1b[0m/a.rs"),
+            b"\x1b[01;34msrc\x1b[0m/a.rs"
+        );
+    }
+
+    #[test]
+    fn test_is_excluded() {
+        let cwd = Path::new("/srv/www");
+        let config = Config {
+            excludes: vec!["target".into(), "*.o".into()],
+            ..Config::default()
+        };
+        let matcher = exclude_matcher(&config, cwd).unwrap();
+        let excluded = |path: &str| is_excluded(&matcher, Path::new(path), cwd);
+        // Under the cwd, a matching parent dir excludes everything in it.
+        assert!(excluded("/srv/www/target"));
+        assert!(excluded("/srv/www/target/debug/founder"));
+        assert!(excluded("/srv/www/sub/target/a.txt"));
+        assert!(excluded("/srv/www/src/main.o"));
+        assert!(!excluded("/srv/www/src/main.rs"));
+        // Elsewhere, only the path itself counts, not the dirs above it.
+        assert!(excluded("/home/me/lib.o"));
+        assert!(excluded("/home/me/target"));
+        assert!(!excluded("/home/me/target/a.txt"));
+        assert!(!excluded("/home/me/notes.txt"));
+    }
+
+    #[test]
+    fn test_is_yes() {
+        for answer in &["y\n", "Y\n", "yes\n", " y \n", "y"] {
+            assert!(is_yes(answer), "{:?}", answer);
+        }
+        for answer in &["\n", "", "n\n", "no\n", "YES\n", "yy\n"] {
+            assert!(!is_yes(answer), "{:?}", answer);
+        }
+    }
+
+    #[test]
+    fn test_query_prefix_matcher() {
+        let matches = |config: &Config, path: &str| query_prefix_matcher(config)(Path::new(path));
+        let mut config = Config {
+            query_prefix: Some("src rs".into()),
+            ..Config::default()
+        };
+        // Every word has to fuzzy match, in any order.
+        assert!(matches(&config, "src/main.rs"));
+        assert!(matches(&config, "tests/resources/srcs.txt"));
+        assert!(!matches(&config, "src/main.py"));
+        assert!(!matches(&config, "lib/main.rs"));
+        config.exact = true;
+        assert!(matches(&config, "src/main.rs"));
+        assert!(!matches(&config, "tests/resources/srcs.txt"));
+        config.exact = false;
+        config.match_basename = true;
+        assert!(!matches(&config, "src/main.rs"));
+        assert!(matches(&config, "lib/src_reader.rs"));
+        // Without a prefix, everything matches.
+        assert!(matches(&Config::default(), "anything"));
+    }
+
+    #[test]
+    fn test_format_age() {
+        let now = 1_000_000_000;
+        assert_eq!(format_age(now, now), "just now");
+        assert_eq!(format_age(now - 59, now), "just now");
+        assert_eq!(format_age(now - 60, now), "1m ago");
+        assert_eq!(format_age(now - 60 * 60 + 1, now), "59m ago");
+        assert_eq!(format_age(now - 60 * 60, now), "1h ago");
+        assert_eq!(format_age(now - 24 * 60 * 60, now), "1d ago");
+        assert_eq!(format_age(now - 400 * 24 * 60 * 60, now), "400d ago");
+        // A timestamp from the future, from a clock change, is just now.
+        assert_eq!(format_age(now + 100, now), "just now");
+    }
+
+    #[test]
+    fn test_strip_annotation() {
+        let config = Config {
+            show_age: true,
+            ..Config::default()
+        };
+        assert_eq!(strip_annotation(&config, b"a.txt\t5m ago, 2x"), b"a.txt");
+        // fd lines get an empty annotation.
+        assert_eq!(strip_annotation(&config, b"a.txt\t"), b"a.txt");
+        assert_eq!(
+            strip_annotation(&config, b"tab\there.txt\t1d ago"),
+            b"tab\there.txt"
+        );
+        // Without --show-age, there's no annotation to strip.
+        let config = Config::default();
+        assert_eq!(
+            strip_annotation(&config, b"tab\there.txt"),
+            b"tab\there.txt"
+        );
+    }
 }
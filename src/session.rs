@@ -0,0 +1,279 @@
+// --record-session saves what went into and came out of each finder pass, as
+// JSON, so that a bug report can include exactly what founder saw: the
+// history, the fd output (or the cached listing, or stdin), the starting
+// query, and what fzf printed. A pass is one run of the finder, and Ctrl-T,
+// Alt-H, or a no at --confirm starts another one. The file is rewritten after
+// every pass, so it's complete even when founder exits with fzf's status.
+//
+// --replay-session runs the finder again on a saved session. The recorded
+// history and candidates stand in for the real ones, fd doesn't run, and a
+// stub in place of fzf reads what founder would've shown and prints what fzf
+// printed the first time, pass after pass. A replay never records selections
+// or compacts the history. It uses the flags it's given, not the recorded
+// ones, which are saved under "args" for the reader, so pass the same flags
+// to reproduce a run. History entries are still checked against the
+// filesystem, so a replay on another machine can skip missing files that the
+// recording showed. JSON strings have to be Unicode, so non-UTF-8 bytes are
+// lossy here, like with --json.
+
+use crate::history;
+use anyhow::{bail, Context, Result};
+use log::debug;
+use once_cell::sync::OnceCell;
+use serde_json::{json, Value};
+use std::env;
+use std::ffi::OsStr;
+use std::fs;
+use std::io::prelude::*;
+use std::io::{self, BufReader};
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+
+const SESSION_VERSION: u64 = 1;
+
+static REPLAY: OnceCell<Replay> = OnceCell::new();
+
+struct Replay {
+    history: Vec<u8>,
+    passes: Vec<ReplayPass>,
+}
+
+pub struct ReplayPass {
+    mode: String,
+    candidates: Vec<Vec<u8>>,
+    fzf_query: Vec<u8>,
+    key: Vec<u8>,
+    selection: Vec<u8>,
+    // None if fzf was killed by a signal.
+    fzf_status: Option<i32>,
+}
+
+// Everything about one pass that goes in the session file.
+pub struct RecordedPass<'a> {
+    pub mode: &'a str,
+    pub query: &'a OsStr,
+    pub source: &'a str,
+    pub candidates: &'a [u8],
+    pub separator: u8,
+    pub fzf_status: ExitStatus,
+    pub fzf_query: &'a [u8],
+    pub key: &'a [u8],
+    pub selection: &'a [u8],
+}
+
+pub struct Recorder {
+    path: PathBuf,
+    session: Value,
+}
+
+impl Recorder {
+    pub fn new(path: &Path, history_bytes: &[u8]) -> Result<Recorder> {
+        let mut history_lines: Vec<&[u8]> =
+            history::history_lines_from_most_recent(history_bytes).collect();
+        history_lines.reverse();
+        let session = json!({
+            "founder_session": SESSION_VERSION,
+            "args": env::args_os().map(|arg| arg.to_string_lossy().into_owned()).collect::<Vec<_>>(),
+            "cwd": env::current_dir()?.to_string_lossy(),
+            "history_null": history_bytes.first() == Some(&history::NULL_FORMAT_HEADER),
+            "history": history_lines.iter().map(|line| lossy(line)).collect::<Vec<_>>(),
+            "passes": [],
+        });
+        Ok(Recorder {
+            path: path.to_owned(),
+            session,
+        })
+    }
+
+    pub fn record_pass(&mut self, pass: RecordedPass) -> Result<()> {
+        let candidates: Vec<String> = pass
+            .candidates
+            .split(|&byte| byte == pass.separator)
+            .filter(|line| !line.is_empty())
+            .map(lossy)
+            .collect();
+        self.session["passes"]
+            .as_array_mut()
+            .expect("passes is an array")
+            .push(json!({
+                "mode": pass.mode,
+                "query": pass.query.to_string_lossy(),
+                "source": pass.source,
+                "candidates": candidates,
+                "fzf": {
+                    "query": lossy(pass.fzf_query),
+                    "key": lossy(pass.key),
+                    "selection": lossy(pass.selection),
+                    "status": pass.fzf_status.code(),
+                },
+            }));
+        let mut bytes = serde_json::to_vec_pretty(&self.session)?;
+        bytes.push(b'\n');
+        fs::write(&self.path, bytes)
+            .with_context(|| format!("failed to write session {}", self.path.display()))
+    }
+}
+
+fn lossy(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).into_owned()
+}
+
+// Loads a session for --replay-session, and changes to the directory it was
+// recorded in, if that's still there, so that relative paths mean the same
+// thing. This needs to come before anything resolves a relative path.
+pub fn start_replay(path: &Path) -> Result<()> {
+    let bytes = fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+    let session: Value = serde_json::from_slice(&bytes)
+        .with_context(|| format!("invalid session file {}", path.display()))?;
+    let (replay, cwd) = parse_session(&session)
+        .and_then(|replay| Ok((replay, get_str(&session, "cwd")?)))
+        .with_context(|| format!("invalid session file {}", path.display()))?;
+    if Path::new(cwd).is_dir() {
+        env::set_current_dir(cwd)?;
+    } else {
+        eprintln!(
+            "founder: {} doesn't exist, replaying in the current directory",
+            cwd
+        );
+    }
+    eprintln!(
+        "founder: replaying {} passes from {}",
+        replay.passes.len(),
+        path.display()
+    );
+    REPLAY
+        .set(replay)
+        .unwrap_or_else(|_| panic!("replay already started"));
+    Ok(())
+}
+
+pub fn replaying() -> bool {
+    REPLAY.get().is_some()
+}
+
+// The recorded history file, rebuilt in whichever format it was in.
+pub fn replay_history() -> Option<&'static [u8]> {
+    REPLAY.get().map(|replay| replay.history.as_slice())
+}
+
+// The recorded pass with this number, counting from 0, or None when we're not
+// replaying. If the replay has gone in a different direction than the
+// recording, that's worth knowing, but it isn't fatal until we run out of
+// recorded passes.
+pub fn replay_pass(number: usize, mode_name: &str) -> Result<Option<&'static ReplayPass>> {
+    let replay = match REPLAY.get() {
+        Some(replay) => replay,
+        None => return Ok(None),
+    };
+    let pass = match replay.passes.get(number) {
+        Some(pass) => pass,
+        None => bail!(
+            "the replayed session ran out after {} passes",
+            replay.passes.len()
+        ),
+    };
+    if pass.mode != mode_name {
+        eprintln!(
+            "founder: pass {} was recorded in {} mode, replaying it in {} mode",
+            number + 1,
+            pass.mode,
+            mode_name
+        );
+    }
+    Ok(Some(pass))
+}
+
+impl ReplayPass {
+    pub fn candidates(&self, separator: u8) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for candidate in &self.candidates {
+            bytes.extend_from_slice(candidate);
+            bytes.push(separator);
+        }
+        bytes
+    }
+
+    // Stands in for fzf. It reads every candidate, like fzf would, and then
+    // prints the recorded output. A selection that isn't among the candidates
+    // anymore means the replay diverged from the recording.
+    pub fn run_stub(
+        &self,
+        candidates: impl Read,
+        separator: u8,
+    ) -> io::Result<(ExitStatus, Vec<u8>)> {
+        let mut found = self.selection.is_empty();
+        let mut count: u64 = 0;
+        for candidate in BufReader::new(candidates).split(separator) {
+            let candidate = candidate?;
+            count += 1;
+            if candidate == self.selection {
+                found = true;
+            }
+        }
+        debug!("replay stub read {} candidates", count);
+        if !found {
+            eprintln!(
+                "founder: the recorded selection {:?} isn't among the replayed candidates",
+                String::from_utf8_lossy(&self.selection)
+            );
+        }
+        let mut output = Vec::new();
+        for part in &[&self.fzf_query, &self.key, &self.selection] {
+            output.extend_from_slice(part);
+            output.push(separator);
+        }
+        let status = match self.fzf_status {
+            Some(code) => ExitStatus::from_raw(code << 8),
+            None => ExitStatus::from_raw(libc::SIGINT),
+        };
+        Ok((status, output))
+    }
+}
+
+fn parse_session(session: &Value) -> Result<Replay> {
+    if session["founder_session"].as_u64() != Some(SESSION_VERSION) {
+        bail!("not a version {} founder session", SESSION_VERSION);
+    }
+    let (separator, mut history) = if session["history_null"].as_bool() == Some(true) {
+        (b'\0', vec![history::NULL_FORMAT_HEADER])
+    } else {
+        (b'\n', Vec::new())
+    };
+    for line in get_array(session, "history")? {
+        history.extend_from_slice(as_str(line, "history")?.as_bytes());
+        history.push(separator);
+    }
+    let mut passes = Vec::new();
+    for pass in get_array(session, "passes")? {
+        let fzf = &pass["fzf"];
+        passes.push(ReplayPass {
+            mode: get_str(pass, "mode")?.to_owned(),
+            candidates: get_array(pass, "candidates")?
+                .iter()
+                .map(|candidate| Ok(as_str(candidate, "candidates")?.as_bytes().to_vec()))
+                .collect::<Result<_>>()?,
+            fzf_query: get_str(fzf, "query")?.as_bytes().to_vec(),
+            key: get_str(fzf, "key")?.as_bytes().to_vec(),
+            selection: get_str(fzf, "selection")?.as_bytes().to_vec(),
+            fzf_status: fzf["status"].as_i64().map(|code| code as i32),
+        });
+    }
+    Ok(Replay { history, passes })
+}
+
+fn get_str<'a>(object: &'a Value, key: &str) -> Result<&'a str> {
+    as_str(&object[key], key)
+}
+
+fn as_str<'a>(value: &'a Value, key: &str) -> Result<&'a str> {
+    value
+        .as_str()
+        .with_context(|| format!("expected a string in {:?}", key))
+}
+
+fn get_array<'a>(object: &'a Value, key: &str) -> Result<&'a Vec<Value>> {
+    object[key]
+        .as_array()
+        .with_context(|| format!("expected an array in {:?}", key))
+}
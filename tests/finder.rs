@@ -0,0 +1,1216 @@
+// These tests run the founder binary with a shell script standing in for fzf,
+// through $FOUNDER_FZF_BIN, so that the whole finder loop runs without a
+// terminal. The stub saves its arguments and its input for each run, prints
+// "q" as the query, the next line of the keys file as the key, and then the
+// first candidate containing $STUB_MATCH. If nothing contains it, it exits
+// with 1, the way fzf does when nothing matches. With $STUB_HEAD, it only
+// reads that many bytes of input, like a user who picks something before fzf
+// has read everything.
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+const STUB: &str = r#"#!/bin/sh
+dir=$(dirname "$0")
+if [ "$1" = --version ]; then
+    echo "0.0.0 (stub)"
+    exit 0
+fi
+n=$(cat "$dir/runs" 2>/dev/null || echo 0)
+echo $((n + 1)) > "$dir/runs"
+printf '%s\n' "$@" > "$dir/args.$n"
+if [ -n "$STUB_HEAD" ]; then
+    head -c "$STUB_HEAD" > "$dir/input.$n"
+else
+    cat > "$dir/input.$n"
+fi
+printf 'q\n%s\n' "$(sed -n "$((n + 1))p" "$dir/keys")"
+grep -m 1 -F -- "$STUB_MATCH" "$dir/input.$n" || exit 1
+"#;
+
+struct Harness {
+    dir: TempDir,
+}
+
+impl Harness {
+    // keys has one line per run of the finder, and an empty line is Enter.
+    fn new(keys: &str) -> Harness {
+        let dir = TempDir::new().unwrap();
+        for sub in &["stub", "history", "cwd"] {
+            fs::create_dir(dir.path().join(sub)).unwrap();
+        }
+        let stub = dir.path().join("stub/fzf");
+        fs::write(&stub, STUB).unwrap();
+        fs::set_permissions(&stub, fs::Permissions::from_mode(0o755)).unwrap();
+        fs::write(dir.path().join("stub/keys"), keys).unwrap();
+        Harness { dir }
+    }
+
+    fn path(&self, relative: &str) -> PathBuf {
+        self.dir.path().join(relative)
+    }
+
+    fn command(&self, stub_match: &str) -> Command {
+        let mut command = self.command_with_tmux(stub_match);
+        command.arg("--no-tmux");
+        command
+    }
+
+    // Like command, but leaves fzf-tmux up to the environment.
+    fn command_with_tmux(&self, stub_match: &str) -> Command {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_founder"));
+        command
+            .current_dir(self.path("cwd"))
+            .env("FOUNDER_FZF_BIN", self.path("stub/fzf"))
+            .env("FOUNDER_HISTORY_DIR", self.path("history"))
+            .env("STUB_MATCH", stub_match)
+            .env_remove("FOUNDER_LOG")
+            .env_remove("FOUNDER_TMUX")
+            .env_remove("FZF_DEFAULT_OPTS")
+            .stderr(Stdio::piped())
+            .stdout(Stdio::piped());
+        command
+    }
+
+    // Runs founder --stdin with these candidates.
+    fn run_stdin(&self, stub_match: &str, candidates: &str, args: &[&str]) -> Output {
+        self.run_stdin_with(self.command(stub_match), candidates, args)
+    }
+
+    // Like run_stdin, for a command that needs more setup first.
+    fn run_stdin_with(&self, mut command: Command, candidates: &str, args: &[&str]) -> Output {
+        let mut child = command
+            .arg("--stdin")
+            .args(args)
+            .stdin(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut stdin = child.stdin.take().unwrap();
+        std::io::Write::write_all(&mut stdin, candidates.as_bytes()).unwrap();
+        drop(stdin);
+        child.wait_with_output().unwrap()
+    }
+
+    // Puts a script with this name in the stub dir, and puts the stub dir at
+    // the front of the command's $PATH.
+    fn stub_on_path(&self, command: &mut Command, name: &str, script: &str) {
+        let stub = self.path("stub").join(name);
+        fs::write(&stub, script).unwrap();
+        fs::set_permissions(&stub, fs::Permissions::from_mode(0o755)).unwrap();
+        let path = std::env::var_os("PATH").unwrap_or_default();
+        let mut dirs = vec![self.path("stub")];
+        dirs.extend(std::env::split_paths(&path));
+        command.env("PATH", std::env::join_paths(dirs).unwrap());
+    }
+
+    // Puts an fd on $PATH that never stops printing, like fd on a huge tree.
+    fn endless_fd(&self, command: &mut Command) {
+        self.stub_on_path(command, "fd", "#!/bin/sh\nexec yes file.txt\n");
+    }
+
+    // Puts an fd on $PATH that saves its arguments and prints nothing.
+    fn recording_fd(&self, command: &mut Command) {
+        let script = format!(
+            "#!/bin/sh\nprintf '%s\\n' \"$@\" > '{}'\n",
+            self.path("stub/fd-args").display()
+        );
+        self.stub_on_path(command, "fd", &script);
+    }
+
+    // What recording_fd saved from the last run of fd.
+    fn fd_args(&self) -> Vec<String> {
+        fs::read_to_string(self.path("stub/fd-args"))
+            .unwrap()
+            .lines()
+            .map(String::from)
+            .collect()
+    }
+
+    // Starts founder in a new session, so that it has no controlling terminal
+    // and can't open /dev/tty, like in a cron job.
+    fn without_terminal(&self, command: &mut Command) {
+        // SAFETY: setsid is async-signal-safe.
+        unsafe {
+            command.pre_exec(|| {
+                if libc::setsid() == -1 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+    }
+
+    // Creates empty files under the cwd, along with their directories.
+    fn touch(&self, files: &[&str]) {
+        for file in files {
+            let path = self.path("cwd").join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+    }
+
+    // The cwd the way history entries spell it, with symlinks resolved.
+    fn cwd(&self) -> String {
+        canonical(&self.path("cwd"))
+    }
+
+    fn write_history(&self, history: &str) {
+        fs::write(self.path("history/file_history"), history).unwrap();
+    }
+
+    // What the stub read on this run, one candidate per line.
+    fn input(&self, run: usize) -> Vec<String> {
+        fs::read_to_string(self.path(&format!("stub/input.{}", run)))
+            .unwrap()
+            .lines()
+            .map(String::from)
+            .collect()
+    }
+
+    fn runs(&self) -> usize {
+        match fs::read_to_string(self.path("stub/runs")) {
+            Ok(runs) => runs.trim().parse().unwrap(),
+            Err(_) => 0,
+        }
+    }
+
+    fn args(&self, run: usize) -> Vec<String> {
+        fs::read_to_string(self.path(&format!("stub/args.{}", run)))
+            .unwrap()
+            .lines()
+            .map(String::from)
+            .collect()
+    }
+
+    fn history(&self) -> String {
+        fs::read_to_string(self.path("history/file_history")).unwrap_or_default()
+    }
+}
+
+// The value following flag in a run's arguments.
+fn arg_value<'a>(args: &'a [String], flag: &str) -> &'a str {
+    let index = args.iter().position(|arg| arg == flag).unwrap();
+    &args[index + 1]
+}
+
+// Whether these arguments appear next to each other in this order.
+fn has_args(args: &[String], expected: &[&str]) -> bool {
+    args.windows(expected.len())
+        .any(|window| window == expected)
+}
+
+fn canonical(path: &Path) -> String {
+    fs::canonicalize(path)
+        .unwrap()
+        .to_string_lossy()
+        .into_owned()
+}
+
+#[test]
+fn test_enter_prints_and_records_the_selection() {
+    let harness = Harness::new("\n");
+    let output = harness.run_stdin("b", "a.txt\nb.txt\n", &[]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "b.txt\n");
+    assert_eq!(harness.runs(), 1);
+    assert_eq!(
+        fs::read_to_string(harness.path("stub/input.0")).unwrap(),
+        "a.txt\nb.txt\n"
+    );
+    let expected = format!("\t{}/b.txt\n", canonical(&harness.path("cwd")));
+    assert!(
+        harness.history().ends_with(&expected),
+        "history: {:?}",
+        harness.history()
+    );
+}
+
+#[test]
+fn test_ctrl_t_switches_modes_and_keeps_the_query() {
+    let harness = Harness::new("ctrl-t\n\n");
+    let output = harness.run_stdin("a", "a.txt\nb.txt\n", &["--print-mode"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "a.txt\n");
+    assert_eq!(String::from_utf8(output.stderr).unwrap(), "global\n");
+    assert_eq!(harness.runs(), 2);
+    let first = harness.args(0);
+    let second = harness.args(1);
+    assert_eq!(arg_value(&first, "--prompt"), "local> ");
+    assert_eq!(arg_value(&first, "--query"), "");
+    assert_eq!(arg_value(&second, "--prompt"), "global> ");
+    // The stub's query from the first run carries over to the second.
+    assert_eq!(arg_value(&second, "--query"), "q");
+    // Both runs got the same candidates from stdin, which is only read once.
+    assert_eq!(
+        fs::read_to_string(harness.path("stub/input.1")).unwrap(),
+        "a.txt\nb.txt\n"
+    );
+}
+
+#[test]
+fn test_no_match_exits_with_1() {
+    let harness = Harness::new("\n");
+    let output = harness.run_stdin("zzz", "a.txt\nb.txt\n", &[]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty());
+    assert_eq!(harness.runs(), 1);
+    assert_eq!(harness.history(), "");
+}
+
+// When fzf exits while fd is still going, founder has to kill fd and unblock
+// the input thread, whichever of its reads or writes it's stuck in. Repeat it
+// a few times, since a hang here would depend on timing.
+#[test]
+fn test_instant_selection_while_fd_is_running() {
+    for _ in 0..20 {
+        let harness = Harness::new("\n");
+        let mut command = harness.command("file");
+        harness.endless_fd(&mut command);
+        let mut child = command
+            .args(["--no-history", "--no-record"])
+            .env("STUB_HEAD", "100")
+            .stdin(Stdio::null())
+            .spawn()
+            .unwrap();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let status = loop {
+            if let Some(status) = child.try_wait().unwrap() {
+                break status;
+            }
+            if Instant::now() > deadline {
+                child.kill().unwrap();
+                panic!("founder hung after fzf exited");
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+        let output = child.wait_with_output().unwrap();
+        assert_eq!(status.code(), Some(0));
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), "file.txt\n");
+    }
+}
+
+#[test]
+fn test_version_with_stdout_closed() {
+    let harness = Harness::new("");
+    let mut child = harness.command("").arg("--version").spawn().unwrap();
+    // Closing the pipe before founder writes anything makes every write a
+    // BrokenPipe, like `founder --version | head -c1` at its worst.
+    drop(child.stdout.take());
+    let output = child.wait_with_output().unwrap();
+    assert_eq!(
+        output.status.code(),
+        Some(0),
+        "stderr: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+#[test]
+fn test_edit_with_a_blank_editor() {
+    let harness = Harness::new("\n");
+    let mut command = harness.command("a");
+    command.env("VISUAL", " ").env_remove("EDITOR");
+    let output = harness.run_stdin_with(command, "a.txt\n", &["--edit"]);
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains("--edit needs $VISUAL or $EDITOR to be set"),
+        "stderr: {}",
+        stderr
+    );
+}
+
+#[test]
+fn test_tiebreak_frecency_puts_frequent_paths_first() {
+    let harness = Harness::new("\n\n");
+    harness.touch(&["often.txt", "recent.txt", "newest.txt"]);
+    let cwd = harness.cwd();
+    harness.write_history(&format!(
+        "1\t5\t{cwd}/often.txt\n2\t{cwd}/recent.txt\n3\t{cwd}/newest.txt\n",
+        cwd = cwd
+    ));
+    harness.run_stdin("txt", "", &["--no-record"]);
+    assert_eq!(harness.input(0), ["newest.txt", "recent.txt", "often.txt"]);
+    let output = harness.run_stdin("txt", "", &["--no-record", "--tiebreak", "frecency"]);
+    assert_eq!(output.status.code(), Some(0));
+    // Equal counts stay in recency order, and fzf keeps our order for ties.
+    assert_eq!(harness.input(1), ["often.txt", "newest.txt", "recent.txt"]);
+    assert!(harness.args(1).contains(&"--tiebreak=index".to_string()));
+}
+
+#[test]
+fn test_record_and_replay_a_session() {
+    let harness = Harness::new("ctrl-t\n\n");
+    let session = harness.path("session.json");
+    let session_arg = session.to_str().unwrap();
+    let output = harness.run_stdin("b", "a.txt\nb.txt\n", &["--record-session", session_arg]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "b.txt\n");
+    assert_eq!(harness.runs(), 2);
+    let recorded = fs::read_to_string(&session).unwrap();
+    assert!(recorded.contains("\"founder_session\": 1"), "{}", recorded);
+    assert!(recorded.contains("\"key\": \"ctrl-t\""), "{}", recorded);
+    assert!(
+        recorded.contains("\"selection\": \"b.txt\""),
+        "{}",
+        recorded
+    );
+    let history = harness.history();
+
+    // The replay doesn't run the stub at all, and it doesn't record.
+    let output = harness.run_stdin("b", "a.txt\nb.txt\n", &["--replay-session", session_arg]);
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert_eq!(output.status.code(), Some(0), "stderr: {}", stderr);
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "b.txt\n");
+    assert!(stderr.contains("replaying 2 passes"), "stderr: {}", stderr);
+    assert_eq!(harness.runs(), 2);
+    assert_eq!(harness.history(), history);
+}
+
+#[test]
+fn test_selection_with_stdout_closed() {
+    let harness = Harness::new("\n");
+    let mut child = harness
+        .command("b")
+        .arg("--stdin")
+        .stdin(Stdio::piped())
+        .spawn()
+        .unwrap();
+    drop(child.stdout.take());
+    let mut stdin = child.stdin.take().unwrap();
+    std::io::Write::write_all(&mut stdin, b"a.txt\nb.txt\n").unwrap();
+    drop(stdin);
+    let output = child.wait_with_output().unwrap();
+    assert_eq!(
+        output.status.code(),
+        Some(0),
+        "stderr: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    // The selection is recorded before it's written.
+    let expected = format!("\t{}/b.txt\n", harness.cwd());
+    assert!(harness.history().ends_with(&expected));
+}
+
+#[test]
+fn test_order() {
+    let harness = Harness::new("\n\n\n\n");
+    harness.touch(&["h1.txt", "h2.txt"]);
+    harness.write_history(&format!(
+        "1\t{cwd}/h1.txt\n2\t{cwd}/h2.txt\n",
+        cwd = harness.cwd()
+    ));
+    let candidates = "f1.txt\nf2.txt\nf3.txt\n";
+    let orders = [
+        (None, ["h2.txt", "h1.txt", "f1.txt", "f2.txt", "f3.txt"]),
+        (
+            Some("history-first"),
+            ["h2.txt", "h1.txt", "f1.txt", "f2.txt", "f3.txt"],
+        ),
+        (
+            Some("fd-first"),
+            ["f1.txt", "f2.txt", "f3.txt", "h2.txt", "h1.txt"],
+        ),
+        (
+            Some("interleaved"),
+            ["h2.txt", "f1.txt", "h1.txt", "f2.txt", "f3.txt"],
+        ),
+    ];
+    for (run, (order, expected)) in orders.iter().enumerate() {
+        let mut args = vec!["--no-record"];
+        if let Some(order) = order {
+            args.extend(&["--order", order]);
+        }
+        let output = harness.run_stdin("txt", candidates, &args);
+        assert_eq!(output.status.code(), Some(0));
+        assert_eq!(harness.input(run), expected, "--order {:?}", order);
+    }
+}
+
+#[test]
+fn test_filter_history() {
+    let harness = Harness::new("\n\n");
+    harness.touch(&["notes.txt", "main.rs"]);
+    harness.write_history(&format!(
+        "1\t{cwd}/notes.txt\n2\t{cwd}/main.rs\n",
+        cwd = harness.cwd()
+    ));
+    let args = ["--no-record", "--fd-glob", "*.txt"];
+    harness.run_stdin("txt", "", &args);
+    assert_eq!(harness.input(0), ["main.rs", "notes.txt"]);
+    let output = harness.run_stdin("txt", "", &[&args[..], &["--filter-history"]].concat());
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(harness.input(1), ["notes.txt"]);
+    // Without a pattern, there's nothing to filter with.
+    let output = harness.run_stdin("txt", "", &["--filter-history"]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("--fd-glob"), "stderr: {}", stderr);
+    assert_eq!(harness.runs(), 2);
+}
+
+#[test]
+fn test_ctrl_o_keeps_the_openers_output_off_stdout() {
+    let harness = Harness::new("ctrl-o\n");
+    harness.touch(&["sub/a.txt"]);
+    let mut command = harness.command("a");
+    let opened = harness.path("opened");
+    let script = format!(
+        "#!/bin/sh\necho \"$1\" > '{}'\necho opening\n",
+        opened.display()
+    );
+    harness.stub_on_path(&mut command, "xdg-open", &script);
+    harness.stub_on_path(&mut command, "open", &script);
+    let output = harness.run_stdin_with(command, "sub/a.txt\n", &[]);
+    assert_eq!(output.status.code(), Some(0));
+    assert!(output.stdout.is_empty());
+    assert_eq!(String::from_utf8(output.stderr).unwrap(), "opening\n");
+    let opened = fs::read_to_string(opened).unwrap();
+    assert_eq!(opened, "sub\n");
+}
+
+#[test]
+fn test_ignore_case_paths() {
+    let harness = Harness::new("\n\n");
+    harness.touch(&["Notes.txt", "notes.txt"]);
+    harness.write_history(&format!(
+        "1\t{cwd}/notes.txt\n2\t{cwd}/Notes.txt\n",
+        cwd = harness.cwd()
+    ));
+    let candidates = "NOTES.TXT\nother.txt\n";
+    harness.run_stdin("txt", candidates, &["--no-record"]);
+    assert_eq!(
+        harness.input(0),
+        ["Notes.txt", "notes.txt", "NOTES.TXT", "other.txt"]
+    );
+    // The most recent spelling wins, and fd's spelling is a duplicate of it.
+    let output = harness.run_stdin("txt", candidates, &["--no-record", "--ignore-case-paths"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(harness.input(1), ["Notes.txt", "other.txt"]);
+}
+
+#[test]
+fn test_exclude_filters_history() {
+    let harness = Harness::new("\n\n");
+    harness.touch(&["target/a.txt", "src/b.txt", "c.log"]);
+    harness.write_history(&format!(
+        "1\t{cwd}/target/a.txt\n2\t{cwd}/src/b.txt\n3\t{cwd}/c.log\n",
+        cwd = harness.cwd()
+    ));
+    harness.run_stdin("txt", "", &["--no-record"]);
+    assert_eq!(harness.input(0), ["c.log", "src/b.txt", "target/a.txt"]);
+    let args = ["--no-record", "--exclude", "target", "--exclude", "*.log"];
+    let output = harness.run_stdin("txt", "", &args);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(harness.input(1), ["src/b.txt"]);
+    // fd gets the same globs.
+    let mut command = harness.command("txt");
+    harness.recording_fd(&mut command);
+    let output = command.args(args).stdin(Stdio::null()).output().unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    let fd_args = harness.fd_args();
+    assert!(
+        has_args(&fd_args, &["--exclude", "target"]),
+        "{:?}",
+        fd_args
+    );
+    assert!(has_args(&fd_args, &["--exclude", "*.log"]), "{:?}", fd_args);
+}
+
+#[test]
+fn test_founderignore() {
+    let harness = Harness::new("\n\n\n");
+    harness.touch(&["secret.txt", "downloads/a.txt", "b.txt"]);
+    harness.write_history(&format!(
+        "1\t{cwd}/secret.txt\n2\t{cwd}/downloads/a.txt\n",
+        cwd = harness.cwd()
+    ));
+    let candidates = "secret2.txt\ndownloads/c.txt\nb.txt\n";
+    harness.run_stdin("txt", candidates, &["--no-record"]);
+    assert_eq!(harness.input(0).len(), 5);
+    // The one in the cwd is rooted there, and it applies to history and fd.
+    fs::write(harness.path("cwd/.founderignore"), "secret*\n").unwrap();
+    harness.run_stdin("txt", candidates, &["--no-record"]);
+    assert_eq!(
+        harness.input(1),
+        ["downloads/a.txt", "downloads/c.txt", "b.txt"]
+    );
+    // The one in the history dir expands ~/. Both files apply, and edits
+    // take effect on the next run.
+    fs::write(harness.path("history/.founderignore"), "~/downloads\n").unwrap();
+    let mut command = harness.command("txt");
+    command.env("HOME", harness.cwd());
+    let output = harness.run_stdin_with(command, candidates, &["--no-record"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(harness.input(2), ["b.txt"]);
+}
+
+#[test]
+fn test_ignore_file() {
+    let harness = Harness::new("\n\n");
+    harness.touch(&["build/a.txt", "b.txt"]);
+    harness.write_history(&format!(
+        "1\t{cwd}/build/a.txt\n2\t{cwd}/b.txt\n",
+        cwd = harness.cwd()
+    ));
+    let ignore_file = harness.path("team-ignore");
+    fs::write(&ignore_file, "build/\n").unwrap();
+    let ignore_file = canonical(&ignore_file);
+    let args = ["--no-record", "--ignore-file", &ignore_file];
+    let output = harness.run_stdin("txt", "", &args);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(harness.input(0), ["b.txt"]);
+    // It goes to fd as an absolute path.
+    let mut command = harness.command("txt");
+    harness.recording_fd(&mut command);
+    command.args(["--no-record", "--ignore-file", "../team-ignore"]);
+    let output = command.stdin(Stdio::null()).output().unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    let fd_args = harness.fd_args();
+    assert!(
+        has_args(&fd_args, &["--ignore-file", &ignore_file]),
+        "{:?}",
+        fd_args
+    );
+    // A missing file is an error up front, before fzf runs.
+    let output = harness.run_stdin("txt", "", &["--ignore-file", "missing"]);
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains(&format!(
+            "--ignore-file {}/missing doesn't exist",
+            harness.cwd()
+        )),
+        "stderr: {}",
+        stderr
+    );
+    assert_eq!(harness.runs(), 2);
+}
+
+#[test]
+fn test_min_query_len() {
+    let harness = Harness::new("ctrl-t\n\n\n");
+    harness.touch(&["a.txt"]);
+    harness.write_history(&format!("1\t{}/a.txt\n", harness.cwd()));
+    let run = |query: Option<&str>| {
+        let mut command = harness.command("a");
+        harness.stub_on_path(&mut command, "fd", "#!/bin/sh\necho fd.txt\n");
+        command.args(["--no-record", "--min-query-len", "2"]);
+        if let Some(query) = query {
+            command.args(["--query", query]);
+        }
+        command.stdin(Stdio::null()).output().unwrap()
+    };
+    let output = run(None);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(harness.runs(), 2);
+    // The first run is history only. Ctrl-T brings in fd, even though the
+    // stub's query is still too short.
+    assert_eq!(harness.input(0), ["a.txt"]);
+    assert_eq!(arg_value(&harness.args(1), "--query"), "q");
+    assert_eq!(harness.input(1), ["a.txt", "fd.txt"]);
+    // A long enough starting query runs fd right away.
+    let output = run(Some("ab"));
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(harness.input(2), ["a.txt", "fd.txt"]);
+}
+
+#[test]
+fn test_path_roots() {
+    let harness = Harness::new("\n");
+    harness.touch(&["a.txt", "../other/b.txt", "../elsewhere/c.txt"]);
+    let other = canonical(&harness.path("other"));
+    let elsewhere = canonical(&harness.path("elsewhere"));
+    harness.write_history(&format!(
+        "1\t{}/a.txt\n2\t{}/b.txt\n3\t{}/c.txt\n",
+        harness.cwd(),
+        other,
+        elsewhere,
+    ));
+    let mut command = harness.command("d.txt");
+    let script = format!(
+        "#!/bin/sh\nprintf '%s\\n' \"$@\" > '{}'\necho '{}/d.txt'\n",
+        harness.path("stub/fd-args").display(),
+        other,
+    );
+    harness.stub_on_path(&mut command, "fd", &script);
+    command.args(["--path", "../other", "--path", "."]);
+    let output = command.stdin(Stdio::null()).output().unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    let fd_args = harness.fd_args();
+    assert!(
+        fd_args.ends_with(&["--".into(), "".into(), other.clone(), harness.cwd()]),
+        "{:?}",
+        fd_args
+    );
+    assert!(!fd_args.contains(&"--strip-cwd-prefix".into()));
+    // History entries under any root show up, and the rest don't.
+    let b = format!("{}/b.txt", other);
+    let d = format!("{}/d.txt", other);
+    assert_eq!(harness.input(0), [&b, "a.txt", &d]);
+    // A selection from fd under the other root is recorded as is.
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        format!("{}\n", d)
+    );
+    assert!(harness.history().ends_with(&format!("\t{}\n", d)));
+}
+
+#[test]
+fn test_keep_duplicates() {
+    let harness = Harness::new("\n\n");
+    harness.touch(&["a.txt", "b.txt"]);
+    harness.write_history(&format!(
+        "100\t{cwd}/a.txt\n200\t3\t{cwd}/b.txt\n300\t{cwd}/a.txt\n",
+        cwd = harness.cwd()
+    ));
+    let args = ["--no-record", "--show-age"];
+    harness.run_stdin("txt", "a.txt\n", &args);
+    let input = harness.input(0);
+    assert_eq!(input.len(), 2);
+    assert!(input[0].starts_with("a.txt\t") && input[0].ends_with(", 2x"));
+    // Every line gets its own candidate, with that line's own count, and fd
+    // lines are still deduped against them.
+    let output = harness.run_stdin(
+        "txt",
+        "a.txt\n",
+        &[&args[..], &["--keep-duplicates"]].concat(),
+    );
+    assert_eq!(output.status.code(), Some(0));
+    let input = harness.input(1);
+    let paths: Vec<_> = input
+        .iter()
+        .map(|line| line.split('\t').next().unwrap())
+        .collect();
+    assert_eq!(paths, ["a.txt", "b.txt", "a.txt"]);
+    assert!(input[1].ends_with(", 3x"), "{:?}", input);
+    assert!(
+        !input[0].ends_with("x") && !input[2].ends_with("x"),
+        "{:?}",
+        input
+    );
+}
+
+#[test]
+fn test_confirm_without_a_terminal() {
+    let harness = Harness::new("\n");
+    let mut command = harness.command("a");
+    harness.without_terminal(&mut command);
+    let output = harness.run_stdin_with(command, "a.txt\n", &["--confirm"]);
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains("--confirm needs a terminal"),
+        "stderr: {}",
+        stderr
+    );
+    // Nothing is printed or recorded without a yes.
+    assert!(output.stdout.is_empty());
+    assert_eq!(harness.history(), "");
+}
+
+#[test]
+fn test_bench() {
+    let harness = Harness::new("");
+    let bench = |args: &[&str]| {
+        let mut command = harness.command("");
+        let fd = "#!/bin/sh\nfind . -type f | sed 's|^\\./||'\n";
+        harness.stub_on_path(&mut command, "fd", fd);
+        command
+            .env("TMPDIR", harness.path("stub"))
+            .arg("bench")
+            .args(args);
+        let output = command.stdin(Stdio::null()).output().unwrap();
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert_eq!(output.status.code(), Some(0), "stderr: {}", stderr);
+        let lines: Vec<String> = stderr.lines().map(String::from).collect();
+        // A header, the column names, one line per run, and the median.
+        assert_eq!(lines.len(), 5, "stderr: {}", stderr);
+        assert!(lines[4].starts_with("med "), "stderr: {}", stderr);
+        lines
+    };
+    let lines = bench(&["--files", "200", "--runs", "2"]);
+    assert_eq!(
+        lines[0],
+        "founder bench: 200 files, 10 history entries, 2 runs"
+    );
+    let lines = bench(&["--compaction", "--lines", "100", "--runs", "2"]);
+    assert_eq!(
+        lines[0],
+        "founder bench: 100 history lines, 100 distinct paths, 2 runs"
+    );
+    let lines = bench(&["--display-paths", "--lines", "100", "--runs", "2"]);
+    assert_eq!(lines[0], "founder bench: 100 paths, 2 runs");
+    // The temp dir is cleaned up, and nothing is recorded.
+    let leftovers: Vec<_> = fs::read_dir(harness.path("stub"))
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .filter(|name| name.starts_with("founder-bench"))
+        .collect();
+    assert!(leftovers.is_empty(), "{:?}", leftovers);
+    assert_eq!(harness.history(), "");
+}
+
+#[test]
+fn test_query_prefix() {
+    let harness = Harness::new("ctrl-t\n\n");
+    let args = ["--query-prefix", "src", "--query", "ma"];
+    let output = harness.run_stdin("rs", "src/main.rs\nlib/main.rs\nREADME\n", &args);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "src/main.rs\n");
+    // The prefix is in the prompt and out of the editable query, in every
+    // mode, and the candidates that don't match it never reach fzf.
+    for run in 0..2 {
+        let args = harness.args(run);
+        assert_eq!(
+            arg_value(&args, "--prompt"),
+            format!("{}> src ", ["local", "global"][run])
+        );
+        assert_eq!(harness.input(run), ["src/main.rs"]);
+    }
+    assert_eq!(arg_value(&harness.args(0), "--query"), "ma");
+    assert_eq!(arg_value(&harness.args(1), "--query"), "q");
+}
+
+#[test]
+fn test_no_terminal() {
+    let harness = Harness::new("");
+    let candidates = harness.path("candidates");
+    fs::write(&candidates, "a.txt\n").unwrap();
+    let run = |stdin: Stdio, args: &[&str]| {
+        let mut command = harness.command("a");
+        command.env_remove("FOUNDER_FZF_BIN");
+        harness.recording_fd(&mut command);
+        harness.without_terminal(&mut command);
+        command
+            .args(["--no-record"])
+            .args(args)
+            .stdin(stdin)
+            .output()
+            .unwrap()
+    };
+    let output = run(Stdio::null(), &[]);
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains("fzf needs a terminal, and there isn't one. Run founder"),
+        "stderr: {}",
+        stderr
+    );
+    // A file on stdin was probably meant to be the candidates.
+    let output = run(fs::File::open(&candidates).unwrap().into(), &[]);
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains("there isn't one (to read candidates from stdin, add --stdin)"),
+        "stderr: {}",
+        stderr
+    );
+    // --builtin doesn't need one.
+    let stdin = fs::File::open(&candidates).unwrap().into();
+    let output = run(stdin, &["--stdin", "--builtin", "--query", "a"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "a.txt\n");
+}
+
+#[test]
+fn test_unwritable_query_history() {
+    // SAFETY: geteuid has no preconditions.
+    if unsafe { libc::geteuid() } == 0 {
+        eprintln!("skipping, permissions don't apply to root");
+        return;
+    }
+    let harness = Harness::new("\n\n");
+    harness.run_stdin("a", "a.txt\n", &[]);
+    let query_history = harness.path("history/query_history");
+    assert_eq!(
+        arg_value(&harness.args(0), "--history"),
+        query_history.to_str().unwrap()
+    );
+    // With a read-only history dir, the file history has to go somewhere else.
+    let _ = fs::remove_file(&query_history);
+    let history_dir = harness.path("history");
+    fs::set_permissions(&history_dir, fs::Permissions::from_mode(0o555)).unwrap();
+    let history_file = harness.path("file_history");
+    let args = ["--history-file", history_file.to_str().unwrap()];
+    let output = harness.run_stdin("a", "a.txt\n", &args);
+    fs::set_permissions(&history_dir, fs::Permissions::from_mode(0o755)).unwrap();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert_eq!(output.status.code(), Some(0), "stderr: {}", stderr);
+    assert!(
+        stderr.contains(&format!(
+            "founder: {} isn't writable, query history is disabled",
+            history_dir.display()
+        )),
+        "stderr: {}",
+        stderr
+    );
+    assert!(!harness.args(1).contains(&"--history".into()));
+    // The selection is still recorded.
+    let expected = format!("\t{}/a.txt\n", harness.cwd());
+    assert!(fs::read_to_string(history_file)
+        .unwrap()
+        .ends_with(&expected));
+}
+
+#[test]
+fn test_color() {
+    let cases: &[(Option<&str>, &[&str], bool)] = &[
+        // stdout is a pipe here, so there's no color by default.
+        (None, &[], false),
+        (None, &["--color"], true),
+        (Some("1"), &[], false),
+        (Some(""), &["--color"], true),
+        // The flags beat NO_COLOR, and the last flag wins.
+        (Some("1"), &["--color"], true),
+        (None, &["--color", "--no-color"], false),
+        (Some("1"), &["--no-color", "--color"], true),
+    ];
+    let harness = Harness::new(&"\n".repeat(cases.len()));
+    for (run, &(no_color, args, colored)) in cases.iter().enumerate() {
+        let mut command = harness.command("a");
+        command.env_remove("NO_COLOR");
+        if let Some(no_color) = no_color {
+            command.env("NO_COLOR", no_color);
+        }
+        let output = harness.run_stdin_with(command, "a.txt\n", &[&["--no-record"], args].concat());
+        assert_eq!(output.status.code(), Some(0));
+        assert_eq!(
+            harness.args(run).contains(&"--color=prompt:blue".into()),
+            colored,
+            "NO_COLOR={:?} {:?}",
+            no_color,
+            args
+        );
+    }
+}
+
+#[test]
+fn test_init_bash() {
+    let harness = Harness::new("");
+    let output = harness.command("").args(["init", "bash"]).output().unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    fs::write(harness.path("init.bash"), output.stdout).unwrap();
+    // Run the widget with a founder on $PATH that selects a path with a space
+    // in it, and then with one that exits like an abort.
+    let widget = |founder: &str| {
+        let mut command = Command::new("bash");
+        harness.stub_on_path(&mut command, "founder", founder);
+        let script = "source init.bash 2> /dev/null\n\
+                      READLINE_LINE='vim  -R'\n\
+                      READLINE_POINT=4\n\
+                      __founder_widget\n\
+                      printf '%s|%s' \"$READLINE_LINE\" \"$READLINE_POINT\"\n";
+        let output = command
+            .current_dir(harness.path(""))
+            .args(["-c", script])
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(0));
+        String::from_utf8(output.stdout).unwrap()
+    };
+    let selected = "#!/bin/sh\n[ \"$1\" = --no-newline ] && printf 'a b.txt'\n";
+    assert_eq!(widget(selected), "vim a\\ b.txt -R|12");
+    assert_eq!(widget("#!/bin/sh\nexit 130\n"), "vim  -R|4");
+    // Other shells are a usage error.
+    let output = harness.command("").args(["init", "tcsh"]).output().unwrap();
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+}
+
+#[test]
+fn test_log_selections() {
+    let harness = Harness::new("\n\n\n");
+    let log = harness.path("history/selection_log");
+    harness.run_stdin("b", "a.txt\nb.txt\n", &[]);
+    assert!(!log.exists());
+    harness.run_stdin("b", "a.txt\nb.txt\n", &["--log-selections", "--no-record"]);
+    assert!(!log.exists());
+    let output = harness.run_stdin("b", "a.txt\nb.txt\n", &["--log-selections"]);
+    assert_eq!(output.status.code(), Some(0));
+    // The stub's query goes between the timestamp and the path, and the
+    // file history is the same as without the flag.
+    let log = fs::read_to_string(log).unwrap();
+    let fields: Vec<&str> = log.trim_end().split('\t').collect();
+    assert_eq!(fields.len(), 3, "{:?}", log);
+    assert!(fields[0].parse::<u64>().is_ok(), "{:?}", log);
+    assert_eq!(fields[1..], ["q", &format!("{}/b.txt", harness.cwd())]);
+    for line in harness.history().lines() {
+        assert!(line.ends_with(&format!("\t{}/b.txt", harness.cwd())));
+        assert_eq!(line.split('\t').count(), 2, "{:?}", line);
+    }
+}
+
+#[test]
+fn test_import() {
+    let harness = Harness::new("");
+    let cwd = harness.cwd();
+    // A full history, so that the import goes over the cap.
+    let old: String = (0..1000).map(|i| format!("{}\t/old/{}\n", i, i)).collect();
+    harness.write_history(&old);
+    let list = harness.path("list");
+    fs::write(&list, "a.txt\nsub/b.txt\n\na.txt\n/etc/hosts\n").unwrap();
+    let output = harness
+        .command("")
+        .arg("import")
+        .arg(&list)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert_eq!(stderr, "founder: imported 3 paths\n");
+    let paths = || -> Vec<String> {
+        harness
+            .history()
+            .lines()
+            .map(|line| line.rsplit('\t').next().unwrap().to_owned())
+            .collect()
+    };
+    // Repeats within the list are dropped, relative paths are under the cwd,
+    // and the compaction afterwards keeps the newest half.
+    let a = format!("{}/a.txt", cwd);
+    let b = format!("{}/sub/b.txt", cwd);
+    let imported = paths();
+    assert_eq!(imported.len(), 500);
+    assert_eq!(imported[497..], [&*a, &b, "/etc/hosts"]);
+
+    // NUL-delimited input from stdin, without compacting afterwards.
+    let mut child = harness
+        .command("")
+        .args(["--no-compact", "import"])
+        .stdin(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    std::io::Write::write_all(&mut stdin, b"c d.txt\0a.txt").unwrap();
+    drop(stdin);
+    let output = child.wait_with_output().unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    let c = format!("{}/c d.txt", cwd);
+    assert_eq!(paths()[497..], [&*a, &b, "/etc/hosts", &c, &a]);
+}
+
+#[test]
+fn test_json() {
+    let harness = Harness::new("ctrl-t\n\n\n");
+    let output = harness.run_stdin("b", "a.txt\nb.txt\n", &["--json"]);
+    assert_eq!(output.status.code(), Some(0));
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    // One object on one line, newline and all.
+    assert!(stdout.ends_with("}\n") && stdout.lines().count() == 1);
+    let object: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+    assert_eq!(
+        object,
+        serde_json::json!({
+            "selection": "b.txt",
+            "query": "q",
+            "mode": "global",
+            "key": "enter",
+        })
+    );
+    // The selection is what would've been printed without --json.
+    let output = harness.run_stdin("b", "a.txt\nb.txt\n", &["--json", "--absolute"]);
+    let object: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(object["selection"], format!("{}/b.txt", harness.cwd()));
+    assert_eq!(object["mode"], "local");
+}
+
+#[test]
+fn test_print_query_only() {
+    let harness = Harness::new("\n\n");
+    let output = harness.run_stdin("b", "a.txt\nb.txt\n", &["--print-query-only"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "q\n");
+    // It doesn't matter whether anything matched, and nothing is recorded.
+    let output = harness.run_stdin("zzz", "a.txt\nb.txt\n", &["--print-query-only"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "q\n");
+    assert_eq!(harness.history(), "");
+    assert!(harness.args(0).contains(&"--print-query".into()));
+}
+
+#[test]
+fn test_show_age() {
+    let harness = Harness::new("\n");
+    harness.touch(&["a.txt"]);
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    harness.write_history(&format!(
+        "{}\t3\t{}/a.txt\n",
+        now - 2 * 60 * 60,
+        harness.cwd()
+    ));
+    let output = harness.run_stdin("a.txt", "a.txt\nb.txt\n", &["--show-age"]);
+    assert_eq!(output.status.code(), Some(0));
+    // History lines say how old they are, fd lines get an empty annotation,
+    // and fzf doesn't match against either.
+    assert_eq!(harness.input(0), ["a.txt\t2h ago, 3x", "b.txt\t"]);
+    assert_eq!(arg_value(&harness.args(0), "--delimiter=\t"), "--nth=..-2");
+    // The selection comes back without its annotation.
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "a.txt\n");
+    assert!(harness
+        .history()
+        .ends_with(&format!("\t{}/a.txt\n", harness.cwd())));
+}
+
+#[test]
+fn test_sort_by_path() {
+    let harness = Harness::new("\n");
+    harness.touch(&["m.txt", "B.txt"]);
+    harness.write_history(&format!(
+        "1\t{cwd}/m.txt\n2\t{cwd}/B.txt\n",
+        cwd = harness.cwd()
+    ));
+    let candidates = "z.txt\nm.txt\na/b.txt\na.txt\n";
+    let output = harness.run_stdin("txt", candidates, &["--no-record", "--sort-by-path"]);
+    assert_eq!(output.status.code(), Some(0));
+    // History and fd sorted together by bytes, after the usual dedup, and
+    // fzf told not to sort them again.
+    assert_eq!(
+        harness.input(0),
+        ["B.txt", "a.txt", "a/b.txt", "m.txt", "z.txt"]
+    );
+    assert!(harness.args(0).contains(&"--no-sort".into()));
+}
+
+#[test]
+fn test_fd_timeout() {
+    let harness = Harness::new("\n\n");
+    let mut command = harness.command("early");
+    // exec, so that killing fd doesn't leave a sleep holding the pipe open.
+    let fd = "#!/bin/sh\necho early.txt\nexec sleep 30\n";
+    harness.stub_on_path(&mut command, "fd", fd);
+    command.args(["--no-record", "--fd-timeout", "1"]);
+    let start = Instant::now();
+    let output = command.stdin(Stdio::null()).output().unwrap();
+    assert!(
+        start.elapsed() < Duration::from_secs(10),
+        "{:?}",
+        start.elapsed()
+    );
+    assert_eq!(output.status.code(), Some(0));
+    // fzf got what fd printed before the timeout.
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "early.txt\n");
+    assert_eq!(harness.input(0), ["early.txt"]);
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert_eq!(stderr, "founder: fd timed out, showing partial results\n");
+    // An fd that finishes in time isn't killed, and founder doesn't wait out
+    // the timeout.
+    let mut command = harness.command("early");
+    harness.stub_on_path(&mut command, "fd", "#!/bin/sh\necho early.txt\n");
+    command.args(["--no-record", "--fd-timeout", "30"]);
+    let start = Instant::now();
+    let output = command.stdin(Stdio::null()).output().unwrap();
+    assert!(
+        start.elapsed() < Duration::from_secs(10),
+        "{:?}",
+        start.elapsed()
+    );
+    assert_eq!(output.status.code(), Some(0));
+    assert!(output.stderr.is_empty());
+}
+
+#[test]
+fn test_max_results() {
+    let harness = Harness::new("\n\n");
+    harness.touch(&["h1.txt", "h2.txt"]);
+    harness.write_history(&format!(
+        "1\t{cwd}/h1.txt\n2\t{cwd}/h2.txt\n",
+        cwd = harness.cwd()
+    ));
+    // History doesn't count toward the cap, and duplicates of it don't
+    // either.
+    let candidates = "h1.txt\nf1.txt\nf2.txt\nf3.txt\n";
+    let output = harness.run_stdin("txt", candidates, &["--no-record", "--max-results", "2"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(harness.input(0), ["h2.txt", "h1.txt", "f1.txt", "f2.txt"]);
+    // Stopping early kills an fd that would never finish.
+    let mut command = harness.command("file");
+    harness.endless_fd(&mut command);
+    command.args(["--no-history", "--no-record", "--max-results", "3"]);
+    let output = command.stdin(Stdio::null()).output().unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(harness.input(1), ["file.txt"; 3]);
+}
+
+#[test]
+fn test_tmux() {
+    let cases: &[(bool, Option<&str>, Option<&str>, bool)] = &[
+        (false, None, None, false),
+        (true, None, None, true),
+        (true, Some("0"), None, false),
+        (true, Some("1"), None, true),
+        // The flags beat FOUNDER_TMUX.
+        (true, Some("0"), Some("--tmux"), true),
+        (true, Some("1"), Some("--no-tmux"), false),
+        // Outside of tmux, there's no fzf-tmux even if it's asked for.
+        (false, Some("1"), Some("--tmux"), false),
+    ];
+    let harness = Harness::new(&"\n".repeat(cases.len()));
+    for (run, &(in_tmux, founder_tmux, flag, tmux)) in cases.iter().enumerate() {
+        let mut command = harness.command_with_tmux("a");
+        command.env_remove("TMUX");
+        if in_tmux {
+            command.env("TMUX", "/tmp/tmux-1000/default,1,0");
+        }
+        if let Some(value) = founder_tmux {
+            command.env("FOUNDER_TMUX", value);
+        }
+        command.args(flag);
+        // The --tmux-opts only go to fzf-tmux, so they show whether we ran it.
+        let args = ["--no-record", "--tmux-opts", "-p"];
+        let output = harness.run_stdin_with(command, "a.txt\n", &args);
+        let case = (in_tmux, founder_tmux, flag);
+        assert_eq!(output.status.code(), Some(0), "{:?}", case);
+        assert_eq!(harness.args(run)[0] == "-p", tmux, "{:?}", case);
+        let warned = String::from_utf8(output.stderr)
+            .unwrap()
+            .contains("--tmux-opts has no effect without --tmux");
+        assert_eq!(warned, !tmux, "{:?}", case);
+    }
+    let mut command = harness.command_with_tmux("a");
+    command.env("FOUNDER_TMUX", "yes");
+    let output = harness.run_stdin_with(command, "a.txt\n", &[]);
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains("FOUNDER_TMUX must be 0 or 1, found \"yes\""),
+        "stderr: {}",
+        stderr
+    );
+}
+
+#[test]
+fn test_clean_py() {
+    let harness = Harness::new("");
+    harness.touch(&["kept.txt"]);
+    let cwd = harness.cwd();
+    let history = format!("1\t{cwd}/kept.txt\n2\t{cwd}/gone.txt\n", cwd = cwd);
+    fs::write(harness.path("history/file_history.work"), &history).unwrap();
+    harness.write_history(&history);
+    let clean = |args: &[&str]| {
+        let output = Command::new("python3")
+            .arg(concat!(env!("CARGO_MANIFEST_DIR"), "/clean.py"))
+            .args(args)
+            .env("FOUNDER_HISTORY_DIR", harness.path("history"))
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{:?}", output);
+    };
+    // It finds the history dir like founder does, and with --profile it only
+    // cleans that profile's history.
+    clean(&["--profile", "work"]);
+    let work = fs::read_to_string(harness.path("history/file_history.work")).unwrap();
+    assert_eq!(work, format!("1\t{}/kept.txt\n", cwd));
+    assert_eq!(harness.history(), history);
+    clean(&[]);
+    assert_eq!(harness.history(), work);
+}